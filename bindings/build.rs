@@ -4,7 +4,7 @@ fn main() {
 
         Windows::Win32::System::Com::{
             COINIT, CLSCTX,
-            CLSIDFromProgID, CoInitializeEx, CoUninitialize, CoCreateInstance,
+            CLSIDFromProgID, CoInitializeEx, CoUninitialize, CoCreateInstance, CoTaskMemFree,
         },
 
         Windows::Win32::System::Threading::{CreateEventW, CreateWaitableTimerW, SetWaitableTimer, CancelWaitableTimer, WaitForMultipleObjects},
@@ -15,7 +15,7 @@ fn main() {
             CloseHandle
         },
 
-        Windows::Win32::Media::Audio::CoreAudio::{MMDeviceEnumerator, IMMDeviceEnumerator, IMMDevice, IMMDeviceCollection, IAudioClient3, IAudioRenderClient, IAudioCaptureClient, AUDCLNT_STREAMFLAGS_LOOPBACK, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, DEVICE_STATE_ACTIVE},
+        Windows::Win32::Media::Audio::CoreAudio::{MMDeviceEnumerator, IMMDeviceEnumerator, IMMDevice, IMMDeviceCollection, IAudioClient3, IAudioRenderClient, IAudioCaptureClient, AUDCLNT_STREAMFLAGS_LOOPBACK, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, DEVICE_STATE_ACTIVE, DEVICE_STATE_DISABLED, DEVICE_STATE_NOTPRESENT, DEVICE_STATEMASK_ALL},
 
         Windows::Win32::Storage::StructuredStorage::STGM_READ,
 
@@ -0,0 +1,102 @@
+use rustfft::{num_complex::Complex32, FftPlanner};
+
+use super::config::{PipelineConfig, PipelineOptions};
+use super::error::{Error, Result};
+use super::fft::FftQueue;
+use super::resample::Resampler;
+use super::source::AudioSource;
+use super::window::Window;
+
+/// パイプラインと同じ窓で FFT して、全ての窓と全てのチャンネルで平均したパワースペクトル
+#[derive(Debug, Clone)]
+pub struct Spectrum {
+    /// 一つの bin の幅 [Hz]
+    pub bin_width: f32,
+    /// 直流から Nyquist 周波数までの各 bin のパワー [dB]
+    pub power_db: Vec<f32>,
+}
+
+impl Spectrum {
+    /// パワーが大きい順に count 個の (周波数 [Hz], パワー [dB]) を返す。直流は除く
+    pub fn peaks(&self, count: usize) -> Vec<(f32, f32)> {
+        let mut peaks: Vec<(f32, f32)> = self
+            .power_db
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(i, power)| {
+                let prev = self.power_db[i - 1];
+                let next = self.power_db.get(i + 1).copied().unwrap_or(f32::MIN);
+                **power >= prev && **power >= next
+            })
+            .map(|(i, power)| (i as f32 * self.bin_width, *power))
+            .collect();
+        peaks.sort_by(|a, b| b.1.total_cmp(&a.1));
+        peaks.truncate(count);
+        peaks
+    }
+}
+
+/// source を最後まで読んで Spectrum を求める
+pub fn analyze<S: AudioSource>(mut source: S, options: &PipelineOptions) -> Result<Spectrum> {
+    let spec = source.start()?;
+    let config = PipelineConfig::new(&spec, options)?;
+
+    let window_size = config.window_size;
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(window_size);
+    let mut buffer = vec![Complex32::new(0.0, 0.0); window_size];
+    let mut power = vec![0.0f64; window_size / 2 + 1];
+    // 窓を掛けても、bin の中心の正弦波のピークの高さが変わらないように係数の和で割る
+    let window = Window::new(&config);
+    let scale = window.sum() * window.sum();
+    let mut count = 0;
+
+    // 長いファイルでもメモリを使い続けないように、読みながら揃った窓から FFT して捨てていく
+    let mut queue = FftQueue::new(config.channels);
+    // 解析の sample rate に揃えてから FFT する
+    let mut resampler = Resampler::new(
+        config.channels,
+        config.device_sample_rate as u32,
+        config.sample_rate as u32,
+    )?;
+    let mut resampled = Vec::new();
+    let mut total_length = 0;
+    let mut index = 0;
+    while let Some(packet) = source.read()? {
+        resampled.clear();
+        resampler.process(&packet.samples, &mut resampled);
+        queue.extend(&resampled);
+        total_length += resampled.len() / config.channels;
+
+        while index + window_size <= total_length {
+            for chan in 0..config.channels {
+                queue.set_buffer(&mut buffer, chan, index, window_size);
+                window.apply(&mut buffer);
+                fft.process(&mut buffer);
+                for (p, v) in power.iter_mut().zip(buffer.iter()) {
+                    *p += (v.norm_sqr() / scale) as f64;
+                }
+                count += 1;
+            }
+            index += config.hop_size;
+        }
+        queue.discard_before(index);
+    }
+    source.stop()?;
+
+    if count == 0 {
+        return Err(Error::Dsp(format!(
+            "input is too short to analyze. frames: {}, window: {}",
+            total_length, window_size
+        )));
+    }
+
+    Ok(Spectrum {
+        bin_width: config.sample_rate as f32 / window_size as f32,
+        power_db: power
+            .iter()
+            .map(|p| (10.0 * (p / count as f64).max(1e-20).log10()) as f32)
+            .collect(),
+    })
+}
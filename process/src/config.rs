@@ -0,0 +1,538 @@
+use hound::WavSpec;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::error::{Error, Result};
+use super::estimator::Estimator;
+use super::resample;
+use super::window::{cosine_sum_terms, WindowFunction};
+
+/// Kaiser 窓の beta の既定値。Blackman 窓と同じくらいの漏れになる
+const DEFAULT_KAISER_BETA: f32 = 8.6;
+
+/// 利用者が指定するパイプラインの設定
+///
+/// サンプル数は wave format が決まるまで分からないので、長さは時間で持つ
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineOptions {
+    /// FFT の窓の長さ [ms]
+    pub window_milli_second: f32,
+    /// FFT の窓をずらす間隔 [ms]
+    pub hop_milli_second: f32,
+    /// FFT の前に掛ける窓関数
+    pub window: WindowFunction,
+    /// window が kaiser のときの beta。大きいほど漏れが小さくなり、ピークが広がる
+    pub kaiser_beta: f32,
+    /// target_freqs の bin を求める方法
+    pub estimator: Estimator,
+    /// 解析のために入力を溜めておくバッファの長さ [ms]。窓はこれに収まる必要がある
+    pub buffer_milli_second: f32,
+    /// 打ち消したい音の周波数 [Hz]。周波数ごとに別の oscillator で打ち消す
+    ///
+    /// 一つだけのときは target_freq = 1000.0 のようにも書ける
+    #[serde(alias = "target_freq", deserialize_with = "one_or_many")]
+    pub target_freqs: Vec<f32>,
+    /// 解析に使う sample rate [Hz]。device の sample rate が違うときは変換する
+    pub analysis_sample_rate: u32,
+    /// FFT を実行するスレッドの数
+    pub worker_count: usize,
+    /// 推定した振幅のずれのうち、一回の更新で反映する割合
+    pub amplitude_gain: f32,
+    /// 推定した位相のずれのうち、一回の更新で反映する割合
+    pub angle_gain: f32,
+    /// 逆位相の音の振幅の上限
+    pub max_amplitude: f32,
+    /// render device に書いた音が capture に戻ってくるまでの遅れ [ms]。resampler の遅れは含めない
+    pub latency_milli_second: f32,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        PipelineOptions {
+            window_milli_second: 5.0,
+            hop_milli_second: 1.0,
+            window: WindowFunction::default(),
+            kaiser_beta: DEFAULT_KAISER_BETA,
+            estimator: Estimator::default(),
+            buffer_milli_second: 1000.0,
+            target_freqs: vec![1000.0],
+            analysis_sample_rate: 48000,
+            worker_count: 8,
+            amplitude_gain: 1.0,
+            angle_gain: 1.0,
+            max_amplitude: 1.0,
+            latency_milli_second: 0.0,
+        }
+    }
+}
+
+impl PipelineOptions {
+    /// wave format に依らずに確かめられる設定の誤りを調べる
+    pub fn validate(&self) -> Result<()> {
+        let positive = [
+            ("window_milli_second", self.window_milli_second),
+            ("hop_milli_second", self.hop_milli_second),
+            ("buffer_milli_second", self.buffer_milli_second),
+        ];
+        let targets = self.target_freqs.iter().map(|freq| ("target_freqs", *freq));
+        for (name, value) in positive.iter().copied().chain(targets) {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(Error::Config(format!(
+                    "{} must be a positive number, got {}",
+                    name, value
+                )));
+            }
+        }
+        let unit = [
+            ("amplitude_gain", self.amplitude_gain),
+            ("angle_gain", self.angle_gain),
+        ];
+        for (name, value) in unit {
+            if !(value > 0.0 && value <= 1.0) {
+                return Err(Error::Config(format!(
+                    "{} must be in (0, 1], got {}",
+                    name, value
+                )));
+            }
+        }
+        if !(self.kaiser_beta.is_finite() && self.kaiser_beta >= 0.0) {
+            return Err(Error::Config(format!(
+                "kaiser_beta must not be negative, got {}",
+                self.kaiser_beta
+            )));
+        }
+        if self.estimator == Estimator::SlidingDft && cosine_sum_terms(self.window).is_none() {
+            return Err(Error::Config(format!(
+                "the sliding-dft estimator does not support the {:?} window",
+                self.window
+            )));
+        }
+        if !(self.max_amplitude.is_finite() && self.max_amplitude >= 0.0) {
+            return Err(Error::Config(format!(
+                "max_amplitude must not be negative, got {}",
+                self.max_amplitude
+            )));
+        }
+        if !(self.latency_milli_second.is_finite() && self.latency_milli_second >= 0.0) {
+            return Err(Error::Config(format!(
+                "latency_milli_second must not be negative, got {}",
+                self.latency_milli_second
+            )));
+        }
+        if self.analysis_sample_rate == 0 {
+            return Err(Error::Config(
+                "analysis_sample_rate must be positive".to_string(),
+            ));
+        }
+        if self.target_freqs.is_empty() {
+            return Err(Error::Config(
+                "target_freqs must have at least one frequency".to_string(),
+            ));
+        }
+        check_nyquist(&self.target_freqs, self.analysis_sample_rate)?;
+        if self.worker_count == 0 {
+            return Err(Error::Config("worker_count must be at least 1".to_string()));
+        }
+        if self.hop_milli_second > self.window_milli_second {
+            return Err(Error::Config(format!(
+                "hop ({} ms) is larger than window ({} ms)",
+                self.hop_milli_second, self.window_milli_second
+            )));
+        }
+        if self.window_milli_second > self.buffer_milli_second {
+            return Err(Error::Config(format!(
+                "window ({} ms) does not fit in the buffer ({} ms)",
+                self.window_milli_second, self.buffer_milli_second
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// 実際の wave format と PipelineOptions から決まる、パイプライン全体で使う設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineConfig {
+    /// 解析に使う sample rate [Hz]。FFT や制御のサンプル数はこれで数える
+    pub sample_rate: usize,
+    /// device や WAV ファイルの sample rate [Hz]
+    pub device_sample_rate: usize,
+    pub channels: usize,
+    /// FFT の窓の長さ [サンプル]
+    pub window_size: usize,
+    /// FFT の窓をずらす間隔 [サンプル]
+    pub hop_size: usize,
+    /// 窓関数を指定する前の記録も読めるように、なければ既定値にする
+    #[serde(default)]
+    pub window: WindowFunction,
+    #[serde(default = "default_kaiser_beta")]
+    pub kaiser_beta: f32,
+    #[serde(default)]
+    pub estimator: Estimator,
+    /// 解析のために入力を溜めておくバッファの長さ [サンプル]
+    pub buffer_size: usize,
+    /// 打ち消したい音の周波数 [Hz]。一つしか指定できなかったときの記録も読めるようにする
+    #[serde(alias = "target_freq", deserialize_with = "one_or_many")]
+    pub target_freqs: Vec<f32>,
+    /// FFT を実行するスレッドの数
+    pub worker_count: usize,
+    pub amplitude_gain: f32,
+    pub angle_gain: f32,
+    pub max_amplitude: f32,
+    /// render と capture の resampler の filter で、生成した音が解析されるまでに遅れる時間 [サンプル]
+    ///
+    /// render device も capture device と同じ sample rate で動いているとみなす。
+    /// replay では変換しないので、記録したときの値をそのまま使う
+    #[serde(default)]
+    pub resampler_delay: f64,
+    /// render device に書いた音が capture に戻ってくるまでの遅れ [サンプル]
+    #[serde(default)]
+    pub latency: f64,
+}
+
+impl PipelineConfig {
+    pub fn new(spec: &WavSpec, options: &PipelineOptions) -> Result<PipelineConfig> {
+        options.validate()?;
+
+        if spec.sample_rate == 0 || spec.channels == 0 {
+            return Err(Error::Format(format!(
+                "unsupported wave format. sample rate: {}, channels: {}",
+                spec.sample_rate, spec.channels
+            )));
+        }
+        // device の sample rate が低いと、解析の sample rate に変換しても target_freqs は含まれない
+        check_nyquist(&options.target_freqs, spec.sample_rate)?;
+        resample::ratio(spec.sample_rate, options.analysis_sample_rate)?;
+
+        let sample_rate = options.analysis_sample_rate as usize;
+        // capture の変換は解析の sample rate、render の変換は device の sample rate で数えた遅れになる
+        let resampler_delay =
+            resample::group_delay(spec.sample_rate, options.analysis_sample_rate)?
+                + resample::group_delay(options.analysis_sample_rate, spec.sample_rate)?
+                    * options.analysis_sample_rate as f64
+                    / spec.sample_rate as f64;
+
+        // 44.1kHz のように 1ms が整数のサンプル数にならない sample rate もあるので丸める
+        let to_samples =
+            |milli_second: f32| (sample_rate as f32 * milli_second / 1000.0).round() as usize;
+
+        let config = PipelineConfig {
+            sample_rate,
+            device_sample_rate: spec.sample_rate as usize,
+            channels: spec.channels as usize,
+            window_size: to_samples(options.window_milli_second).max(1),
+            hop_size: to_samples(options.hop_milli_second).max(1),
+            window: options.window,
+            kaiser_beta: options.kaiser_beta,
+            estimator: options.estimator,
+            buffer_size: to_samples(options.buffer_milli_second).max(1),
+            target_freqs: options.target_freqs.clone(),
+            worker_count: options.worker_count,
+            amplitude_gain: options.amplitude_gain,
+            angle_gain: options.angle_gain,
+            max_amplitude: options.max_amplitude,
+            resampler_delay,
+            latency: sample_rate as f64 * options.latency_milli_second as f64 / 1000.0,
+        };
+
+        // 同じ bin の周波数は区別できず、二つの oscillator が同じ音を打ち消し合ってしまう
+        for target in 1..config.target_freqs.len() {
+            let index = config.target_freq_index(target);
+            if let Some(other) = (0..target).find(|other| config.target_freq_index(*other) == index)
+            {
+                return Err(Error::Config(format!(
+                    "target_freqs {} Hz and {} Hz fall in the same FFT bin (bin width: {} Hz)",
+                    config.target_freqs[other],
+                    config.target_freqs[target],
+                    config.bin_width()
+                )));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// FFT の窓の長さ [ms]
+    pub fn window_milli_second(&self) -> u128 {
+        self.to_milli_second(self.window_size)
+    }
+
+    /// FFT の一つの bin の幅 [Hz]
+    pub fn bin_width(&self) -> f32 {
+        self.sample_rate as f32 / self.window_size as f32
+    }
+
+    /// target 番目の target_freqs に一番近い FFT の bin
+    pub fn target_freq_index(&self, target: usize) -> usize {
+        (self.target_freqs[target] / self.bin_width()).round() as usize
+    }
+
+    /// 生成した音が解析されるまでに遅れる時間 [サンプル]
+    pub fn loop_delay(&self) -> f64 {
+        self.resampler_delay + self.latency
+    }
+
+    /// サンプル数を時間 [ms] に直す
+    pub fn to_milli_second(&self, samples: usize) -> u128 {
+        (samples * 1000 / self.sample_rate) as u128
+    }
+}
+
+/// 打ち消したい音をどこから取り込むか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureMode {
+    /// render device に出ている音を loopback で取り込む
+    #[default]
+    Loopback,
+    /// マイクなどの capture device から取り込む
+    Microphone,
+}
+
+/// live で動かすときの設定
+#[derive(Debug, Clone, Default)]
+pub struct SessionOptions {
+    /// 使う render device の ID か名前の一部。None なら既定の device
+    pub device: Option<String>,
+    pub capture: CaptureMode,
+    /// Microphone のときに使う capture device の ID か名前の一部。None なら既定の device
+    pub capture_device: Option<String>,
+    /// 動かす時間。None なら止められるまで動かし続ける
+    pub duration: Option<Duration>,
+    /// 指定すると capture の音 (逆位相の音が足された残差) と、そこで聞こえるはずの逆位相の音をこの WAV に記録し、設定を同じ名前の .json に書き出す
+    pub record: Option<PathBuf>,
+    pub pipeline: PipelineOptions,
+}
+
+/// filterg.toml の中身。書かれていない項目は既定値になる
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SessionFile {
+    device: Option<String>,
+    #[serde(default)]
+    capture: CaptureMode,
+    capture_device: Option<String>,
+    /// 動かす時間 [s]。書かれていなければ止められるまで動かし続ける
+    duration_second: Option<f32>,
+    record: Option<PathBuf>,
+    #[serde(default)]
+    pipeline: PipelineOptions,
+}
+
+impl SessionOptions {
+    /// TOML ファイルから設定を読み込んで検証する
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SessionOptions> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+        // どのファイルの誤りか分かるようにする
+        SessionOptions::from_toml(&text).map_err(|e| match e {
+            Error::Config(msg) => Error::Config(format!("{}: {}", path.display(), msg)),
+            e => e,
+        })
+    }
+
+    /// TOML の文字列から設定を読み込んで検証する
+    pub fn from_toml(text: &str) -> Result<SessionOptions> {
+        let file: SessionFile = toml::from_str(text).map_err(|e| Error::Config(e.to_string()))?;
+
+        // loopback は render device から取り込むので、capture device を指定しても使われない
+        if file.capture == CaptureMode::Loopback && file.capture_device.is_some() {
+            return Err(Error::Config(
+                "capture_device requires capture = \"microphone\"".to_string(),
+            ));
+        }
+
+        let mut session = SessionOptions {
+            device: file.device,
+            capture: file.capture,
+            capture_device: file.capture_device,
+            record: file.record,
+            pipeline: file.pipeline,
+            ..SessionOptions::default()
+        };
+        if let Some(duration) = file.duration_second {
+            session.duration = Duration::try_from_secs_f32(duration)
+                .map(Some)
+                .map_err(|_| {
+                    Error::Config(format!(
+                        "duration_second must not be negative, got {}",
+                        duration
+                    ))
+                })?;
+        }
+        session.pipeline.validate()?;
+
+        Ok(session)
+    }
+}
+
+fn default_kaiser_beta() -> f32 {
+    DEFAULT_KAISER_BETA
+}
+
+/// 一つの数でも数の配列でも読めるようにする
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<f32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(f32),
+        Many(Vec<f32>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn check_nyquist(target_freqs: &[f32], sample_rate: u32) -> Result<()> {
+    let nyquist = sample_rate as f32 / 2.0;
+    for target_freq in target_freqs {
+        if *target_freq >= nyquist {
+            return Err(Error::Config(format!(
+                "target_freq ({} Hz) is not below the Nyquist frequency ({} Hz)",
+                target_freq, nyquist
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(sample_rate: u32) -> WavSpec {
+        WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        }
+    }
+
+    /// options を 48kHz の device で使ったときの誤りのメッセージ
+    fn error(options: PipelineOptions) -> String {
+        match PipelineConfig::new(&spec(48000), &options) {
+            Err(Error::Config(msg)) => msg,
+            result => panic!("expected a config error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn default_is_valid() {
+        let config = PipelineConfig::new(&spec(48000), &PipelineOptions::default()).unwrap();
+        assert_eq!(config.window_size, 240);
+        assert_eq!(config.hop_size, 48);
+        assert_eq!(config.resampler_delay, 0.0);
+    }
+
+    #[test]
+    fn rejects_targets_above_nyquist() {
+        let msg = error(PipelineOptions {
+            target_freqs: vec![1000.0, 24000.0],
+            ..Default::default()
+        });
+        assert!(msg.contains("Nyquist"), "{}", msg);
+
+        // 解析の sample rate では足りていても、device の sample rate で足りなければ取り込めない
+        let options = PipelineOptions {
+            target_freqs: vec![12000.0],
+            ..Default::default()
+        };
+        assert!(PipelineConfig::new(&spec(22050), &options).is_err());
+    }
+
+    #[test]
+    fn rejects_hop_larger_than_window() {
+        let msg = error(PipelineOptions {
+            window_milli_second: 5.0,
+            hop_milli_second: 6.0,
+            ..Default::default()
+        });
+        assert!(msg.contains("hop"), "{}", msg);
+    }
+
+    #[test]
+    fn rejects_window_larger_than_buffer() {
+        let msg = error(PipelineOptions {
+            window_milli_second: 50.0,
+            buffer_milli_second: 40.0,
+            ..Default::default()
+        });
+        assert!(msg.contains("buffer"), "{}", msg);
+    }
+
+    #[test]
+    fn rejects_targets_in_the_same_bin() {
+        // 5ms の窓では bin の幅が 200 Hz になる
+        let msg = error(PipelineOptions {
+            target_freqs: vec![1000.0, 1050.0],
+            window_milli_second: 5.0,
+            ..Default::default()
+        });
+        assert!(msg.contains("same FFT bin"), "{}", msg);
+
+        // 窓を長くすれば区別できる
+        let options = PipelineOptions {
+            target_freqs: vec![1000.0, 1050.0],
+            window_milli_second: 40.0,
+            ..Default::default()
+        };
+        assert!(PipelineConfig::new(&spec(48000), &options).is_ok());
+    }
+
+    #[test]
+    fn rejects_gains_outside_unit_interval() {
+        for gain in [0.0, -0.5, 1.5, f32::NAN] {
+            let msg = error(PipelineOptions {
+                amplitude_gain: gain,
+                ..Default::default()
+            });
+            assert!(msg.contains("amplitude_gain"), "{}: {}", gain, msg);
+            let msg = error(PipelineOptions {
+                angle_gain: gain,
+                ..Default::default()
+            });
+            assert!(msg.contains("angle_gain"), "{}: {}", gain, msg);
+        }
+        let options = PipelineOptions {
+            amplitude_gain: 1.0,
+            angle_gain: 0.1,
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        for text in [
+            "unknown = 1",
+            "[pipeline]\nwindow_ms = 5.0",
+            "[pipeline]\ntarget_freqs = [1000.0]\n[other]",
+        ] {
+            assert!(
+                matches!(SessionOptions::from_toml(text), Err(Error::Config(_))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn reads_toml() {
+        let session = SessionOptions::from_toml(
+            "capture = \"microphone\"\nduration_second = 1.5\n[pipeline]\ntarget_freq = 500.0\nlatency_milli_second = 2.0",
+        )
+        .unwrap();
+        assert_eq!(session.capture, CaptureMode::Microphone);
+        assert_eq!(session.duration, Some(Duration::from_millis(1500)));
+        assert_eq!(session.pipeline.target_freqs, vec![500.0]);
+        assert_eq!(session.pipeline.latency_milli_second, 2.0);
+    }
+}
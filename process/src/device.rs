@@ -1,51 +1,181 @@
-use bindings::{
-    Windows::Win32::Media::Audio::CoreAudio::{
-        eConsole, eRender, IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator,
-    },
-    Windows::Win32::{
-        Media::Audio::CoreAudio::DEVICE_STATE_ACTIVE,
-        Storage::StructuredStorage::STGM_READ,
-        System::{
-            Com::{CoCreateInstance, CLSCTX_ALL},
-            SystemServices::DEVPKEY_Device_FriendlyName,
-        },
-    },
-};
-
-use super::utils::from_wide_ptr;
-
-pub fn get_default_device() -> windows::Result<IMMDevice> {
-    let mm_device_enumerator: IMMDeviceEnumerator =
-        unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)? };
-
-    let default_device =
-        unsafe { mm_device_enumerator.GetDefaultAudioEndpoint(eRender, eConsole)? };
-
-    Ok(default_device)
-}
-
-pub fn get_list_devices() -> windows::Result<u8> {
-    let mm_device_enumerator: IMMDeviceEnumerator =
-        unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)? };
-
-    let mm_device_collection =
-        unsafe { mm_device_enumerator.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)? };
-
-    let count = unsafe { mm_device_collection.GetCount()? };
-
-    for i in 0..count {
-        let mm_device = unsafe { mm_device_collection.Item(i)? };
-        let property_store = unsafe { mm_device.OpenPropertyStore(STGM_READ as u32)? };
-        let pv = unsafe { property_store.GetValue(&DEVPKEY_Device_FriendlyName)? };
-        unsafe {
-            println!(
-                "{:#?}",
-                from_wide_ptr(pv.Anonymous.Anonymous.Anonymous.pwszVal.0)
-            )
+use hound::WavSpec;
+
+use super::error::{Error, Result};
+
+/// endpoint の向き
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// スピーカーなど、音を出す device。loopback で capture するのもこちら
+    Render,
+    /// マイクなど、音を取り込む device
+    Capture,
+}
+
+/// endpoint の状態。値は WASAPI の DEVICE_STATE_* に対応する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    Active,
+    Disabled,
+    NotPresent,
+    Unplugged,
+}
+
+/// 一つの endpoint の情報
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub id: String,
+    /// 表示用の名前 (friendly name)
+    pub name: String,
+    pub direction: Direction,
+    pub state: DeviceState,
+    /// その向きの既定の device かどうか
+    pub is_default: bool,
+    /// 共有モードで使う形式。取得できなかったときや扱えない形式のときは None
+    pub mix_format: Option<WavSpec>,
+}
+
+/// endpoint の一覧を返すもの
+///
+/// WASAPI の実装の他に、Windows 以外でも選択の処理を確かめられるように MockEnumerator がある
+pub trait DeviceEnumerator {
+    fn devices(&self) -> Result<Vec<DeviceInfo>>;
+}
+
+/// 決まった一覧を返す enumerator
+#[derive(Debug, Clone, Default)]
+pub struct MockEnumerator {
+    pub devices: Vec<DeviceInfo>,
+}
+
+impl DeviceEnumerator for MockEnumerator {
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        Ok(self.devices.clone())
+    }
+}
+
+/// direction の有効な device から query に合うものを一つ選ぶ
+///
+/// query が None なら既定の device、ID と完全に一致するものがあればそれ、なければ名前に query を含むもの (大文字小文字は区別しない)。
+/// 名前で複数見つかったときはどれか決められないのでエラーにする
+pub fn select_device<E: DeviceEnumerator + ?Sized>(
+    enumerator: &E,
+    direction: Direction,
+    query: Option<&str>,
+) -> Result<DeviceInfo> {
+    let candidates: Vec<DeviceInfo> = enumerator
+        .devices()?
+        .into_iter()
+        .filter(|device| device.direction == direction && device.state == DeviceState::Active)
+        .collect();
+
+    let query = match query {
+        Some(query) => query,
+        None => {
+            return candidates
+                .into_iter()
+                .find(|device| device.is_default)
+                .ok_or_else(|| Error::Device(format!("no default {:?} device", direction)))
+        }
+    };
+
+    if let Some(device) = candidates.iter().find(|device| device.id == query) {
+        return Ok(device.clone());
+    }
+
+    let lower = query.to_lowercase();
+    let mut matched: Vec<DeviceInfo> = candidates
+        .into_iter()
+        .filter(|device| device.name.to_lowercase().contains(&lower))
+        .collect();
+    match matched.len() {
+        0 => Err(Error::Device(format!(
+            "no active {:?} device matches \"{}\"",
+            direction, query
+        ))),
+        1 => Ok(matched.remove(0)),
+        _ => Err(Error::Device(format!(
+            "\"{}\" matches more than one {:?} device: {}",
+            query,
+            direction,
+            matched
+                .iter()
+                .map(|device| device.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: &str, name: &str, direction: Direction, is_default: bool) -> DeviceInfo {
+        DeviceInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+            direction,
+            state: DeviceState::Active,
+            is_default,
+            mix_format: None,
+        }
+    }
+
+    fn enumerator() -> MockEnumerator {
+        let mut unplugged = device("r3", "USB Headphones", Direction::Render, false);
+        unplugged.state = DeviceState::Unplugged;
+        MockEnumerator {
+            devices: vec![
+                device("r1", "Speakers (Realtek Audio)", Direction::Render, false),
+                device("r2", "Headphones (Realtek Audio)", Direction::Render, true),
+                unplugged,
+                device("c1", "Microphone (USB Audio)", Direction::Capture, true),
+                device("c2", "Line In (Realtek Audio)", Direction::Capture, false),
+            ],
+        }
+    }
+
+    fn select(direction: Direction, query: Option<&str>) -> Result<String> {
+        select_device(&enumerator(), direction, query).map(|device| device.id)
+    }
+
+    #[test]
+    fn default_per_direction() {
+        assert_eq!(select(Direction::Render, None).unwrap(), "r2");
+        assert_eq!(select(Direction::Capture, None).unwrap(), "c1");
+    }
+
+    #[test]
+    fn no_default() {
+        let enumerator = MockEnumerator {
+            devices: vec![device("r1", "Speakers", Direction::Render, false)],
         };
+        assert!(select_device(&enumerator, Direction::Render, None).is_err());
+        assert!(select_device(&enumerator, Direction::Capture, None).is_err());
+    }
 
-        unsafe { println!("{:#?}", from_wide_ptr(mm_device.GetId()?.0)) };
+    #[test]
+    fn by_id() {
+        assert_eq!(select(Direction::Render, Some("r1")).unwrap(), "r1");
+        // 向きが違う device の ID は選ばない
+        assert!(select(Direction::Render, Some("c1")).is_err());
     }
 
-    Ok(0)
+    #[test]
+    fn by_name_substring() {
+        assert_eq!(select(Direction::Render, Some("speakers")).unwrap(), "r1");
+        assert_eq!(select(Direction::Capture, Some("USB")).unwrap(), "c1");
+    }
+
+    #[test]
+    fn ambiguous_name() {
+        let error = select(Direction::Render, Some("realtek")).unwrap_err();
+        assert!(error.to_string().contains("Speakers (Realtek Audio)"));
+    }
+
+    #[test]
+    fn inactive_devices_are_ignored() {
+        assert!(select(Direction::Render, Some("r3")).is_err());
+        assert!(select(Direction::Render, Some("USB")).is_err());
+    }
 }
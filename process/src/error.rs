@@ -0,0 +1,160 @@
+use std::fmt;
+
+/// filterg の処理で起きるエラー
+///
+/// どのスレッドで起きたエラーも、この型で呼び出し元まで返す
+#[derive(Debug)]
+pub enum Error {
+    /// WASAPI や COM の API が返したエラー
+    #[cfg(windows)]
+    Windows(windows::Error),
+    /// オーディオデバイスが想定外の振る舞いをした
+    Device(String),
+    /// 扱えない wave format や、チャンネル数の合わないデータ
+    Format(String),
+    /// スレッド間のチャンネルの相手がいなくなった。どのチャンネルかを持つ
+    ChannelDisconnected(&'static str),
+    /// スレッドが panic した。どのスレッドかを持つ
+    ThreadPanicked(&'static str),
+    /// 設定の誤り
+    Config(String),
+    Io(std::io::Error),
+    Wav(hound::Error),
+    /// FFT や制御の計算ができない
+    Dsp(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// プロセスの終了コード。呼び出し側が原因を区別できるように、sysexits.h の値を使う
+    ///
+    /// sysexits.h は原因の種類ごとの値なので、同じ種類のエラーは同じ値になる。
+    /// デバイスが使えない (Windows, Device)、入力のデータが扱えない (Format, Wav)、
+    /// filterg 自身の不具合 (ChannelDisconnected, ThreadPanicked, Dsp)、設定の誤り (Config)、入出力の失敗 (Io)。
+    /// 種類の中のどれかはエラーのメッセージで区別する
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            // EX_UNAVAILABLE
+            #[cfg(windows)]
+            Error::Windows(_) => 69,
+            Error::Device(_) => 69,
+            // EX_DATAERR
+            Error::Format(_) | Error::Wav(_) => 65,
+            // EX_SOFTWARE
+            Error::ChannelDisconnected(_) | Error::ThreadPanicked(_) | Error::Dsp(_) => 70,
+            // EX_CONFIG
+            Error::Config(_) => 78,
+            // EX_IOERR
+            Error::Io(_) => 74,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(windows)]
+            Error::Windows(e) => write!(f, "windows error. {}", e),
+            Error::Device(msg) => write!(f, "device error. {}", msg),
+            Error::Format(msg) => write!(f, "unsupported format. {}", msg),
+            Error::ChannelDisconnected(name) => write!(f, "{} channel is disconnected", name),
+            Error::ThreadPanicked(name) => write!(f, "{} thread panicked", name),
+            Error::Config(msg) => write!(f, "invalid configuration. {}", msg),
+            Error::Io(e) => write!(f, "io error. {}", e),
+            Error::Wav(e) => write!(f, "wav error. {}", e),
+            Error::Dsp(msg) => write!(f, "dsp error. {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(windows)]
+            Error::Windows(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Wav(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<windows::Error> for Error {
+    fn from(e: windows::Error) -> Self {
+        Error::Windows(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<hound::Error> for Error {
+    fn from(e: hound::Error) -> Self {
+        Error::Wav(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn categories_have_distinct_exit_codes() {
+        // 同じ種類のエラーを並べる
+        let categories = [
+            vec![
+                #[cfg(windows)]
+                Error::Windows(windows::Error::OK),
+                Error::Device(String::new()),
+            ],
+            vec![
+                Error::Format(String::new()),
+                Error::Wav(hound::Error::Unsupported),
+            ],
+            vec![
+                Error::ChannelDisconnected("test"),
+                Error::ThreadPanicked("test"),
+                Error::Dsp(String::new()),
+            ],
+            vec![Error::Config(String::new())],
+            vec![Error::Io(std::io::Error::other("test"))],
+        ];
+        // 新しい variant を足したら、ここで番号を振って categories のどれかに入れる
+        let variant = |error: &Error| match error {
+            Error::Device(_) => 0,
+            Error::Format(_) => 1,
+            Error::ChannelDisconnected(_) => 2,
+            Error::ThreadPanicked(_) => 3,
+            Error::Config(_) => 4,
+            Error::Io(_) => 5,
+            Error::Wav(_) => 6,
+            Error::Dsp(_) => 7,
+            #[cfg(windows)]
+            Error::Windows(_) => 8,
+        };
+        let mut covered = Vec::new();
+        let mut codes = Vec::new();
+        for errors in categories.iter() {
+            let code = errors[0].exit_code();
+            for error in errors.iter() {
+                assert_eq!(error.exit_code(), code, "{}", error);
+                covered.push(variant(error));
+            }
+            codes.push(code);
+        }
+        covered.sort();
+        let expected: Vec<usize> = (0..if cfg!(windows) { 9 } else { 8 }).collect();
+        assert_eq!(covered, expected);
+
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), categories.len());
+        // 1 は失敗全般、2 は clap の使い方の誤りに使われる
+        assert!(codes.iter().all(|code| *code > 2));
+    }
+}
@@ -0,0 +1,148 @@
+use rustfft::num_complex::{Complex32, Complex64};
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::config::PipelineConfig;
+
+/// 窓を掛けた buffer から target_freqs の bin を求める方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Estimator {
+    /// 全ての bin を FFT で求めて、必要な bin だけを使う
+    #[default]
+    Fft,
+    /// 必要な bin だけを Goertzel 法で求める。target_freqs が少ないときは FFT より速い
+    Goertzel,
+    /// 必要な bin を 1 サンプルごとに更新し続ける。hop を短くしても軽い。cos の和で表せる窓だけを扱える
+    SlidingDft,
+}
+
+impl FromStr for Estimator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fft" => Ok(Estimator::Fft),
+            "goertzel" => Ok(Estimator::Goertzel),
+            "sliding-dft" => Ok(Estimator::SlidingDft),
+            _ => Err(format!(
+                "unknown estimator \"{}\". expected fft, goertzel or sliding-dft",
+                s
+            )),
+        }
+    }
+}
+
+/// 一つの窓から target_freqs の bin を求める。どの方法でも FFT の同じ bin と同じ値になる
+///
+/// SlidingDft は窓ごとに求めるものではないので、ここでは同じ値になる Goertzel 法を使う
+#[derive(Clone)]
+pub struct BinEstimator {
+    /// target ごとの bin
+    indices: Vec<usize>,
+    method: Method,
+}
+
+#[derive(Clone)]
+enum Method {
+    Fft(Arc<dyn Fft<f32>>),
+    /// target ごとの Goertzel 法の係数 2cos(ω) と、最後に掛ける e^{jω}
+    Goertzel(Vec<(f64, Complex64)>),
+}
+
+impl BinEstimator {
+    pub fn new(config: &PipelineConfig) -> BinEstimator {
+        let indices: Vec<usize> = (0..config.target_freqs.len())
+            .map(|target| config.target_freq_index(target))
+            .collect();
+        let method = match config.estimator {
+            Estimator::Fft => Method::Fft(FftPlanner::new().plan_fft_forward(config.window_size)),
+            Estimator::Goertzel | Estimator::SlidingDft => Method::Goertzel(
+                indices
+                    .iter()
+                    .map(|index| {
+                        let omega = 2.0 * PI * *index as f64 / config.window_size as f64;
+                        (2.0 * omega.cos(), Complex64::from_polar(1.0, omega))
+                    })
+                    .collect(),
+            ),
+        };
+        BinEstimator { indices, method }
+    }
+
+    /// 窓を掛けた buffer の、target ごとの bin を values に入れる
+    ///
+    /// Goertzel 法は実部だけを使う。FFT のときは buffer が FFT の結果で上書きされる
+    pub fn estimate(&self, buffer: &mut [Complex32], values: &mut Vec<Complex32>) {
+        values.clear();
+        match &self.method {
+            Method::Fft(fft) => {
+                fft.process(buffer);
+                values.extend(self.indices.iter().map(|index| buffer[*index]));
+            }
+            Method::Goertzel(coefficients) => {
+                values.extend(coefficients.iter().map(|(coefficient, twiddle)| {
+                    // 長い窓でも誤差が溜まらないように f64 で計算する
+                    let (mut s1, mut s2) = (0.0, 0.0);
+                    for value in buffer.iter() {
+                        let s = value.re as f64 + coefficient * s1 - s2;
+                        s2 = s1;
+                        s1 = s;
+                    }
+                    let bin = twiddle * s1 - s2;
+                    Complex32::new(bin.re as f32, bin.im as f32)
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::input;
+
+    fn config(estimator: Estimator) -> PipelineConfig {
+        crate::test_utils::config(
+            1,
+            crate::PipelineOptions {
+                estimator,
+                target_freqs: vec![200.0, 1000.0, 3020.0, 23000.0],
+                window_milli_second: 20.0,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn estimate(estimator: Estimator, input: &[f32]) -> Vec<Complex32> {
+        let mut buffer: Vec<Complex32> = input.iter().map(|x| Complex32::new(*x, 0.0)).collect();
+        let mut values = Vec::new();
+        BinEstimator::new(&config(estimator)).estimate(&mut buffer, &mut values);
+        values
+    }
+
+    #[test]
+    fn goertzel_matches_fft() {
+        let size = config(Estimator::Fft).window_size;
+        let input = input(size, 1);
+        let fft = estimate(Estimator::Fft, &input);
+        let goertzel = estimate(Estimator::Goertzel, &input);
+        assert_eq!(fft.len(), 4);
+        for (f, g) in fft.iter().zip(goertzel.iter()) {
+            assert!((f - g).norm() < 1e-3, "fft: {}, goertzel: {}", f, g);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        for estimator in [Estimator::Fft, Estimator::Goertzel, Estimator::SlidingDft] {
+            let name = serde_json::to_value(estimator).unwrap();
+            let parsed: Estimator = name.as_str().unwrap().parse().unwrap();
+            assert_eq!(parsed, estimator);
+        }
+        assert!("dft".parse::<Estimator>().is_err());
+    }
+}
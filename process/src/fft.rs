@@ -1,23 +1,37 @@
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     sync::{
-        atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
+        atomic::{
+            AtomicUsize,
+            Ordering::{Acquire, Release},
+        },
         mpsc::{channel, Receiver, RecvTimeoutError, Sender},
-        Arc, RwLock,
+        Arc,
     },
     thread,
 };
 
-use rustfft::{num_complex::Complex32, Fft, FftPlanner};
+use rustfft::num_complex::Complex32;
 
 use plotters::prelude::*;
 
-use super::utils::{HOP_SIZE, TARGET_FREQ_INDEX, WINDOW_SIZE};
-
-use super::utils::get_now_unix_time;
-
+use super::config::PipelineConfig;
+use super::error::{Error, Result};
+use super::estimator::{BinEstimator, Estimator};
+use super::packet::Packet;
+use super::record::RecordEvent;
+use super::resample::Resampler;
+use super::ring::RingBuffer;
+use super::sliding_dft::sliding_dft_thread_func;
+use super::window::Window;
+
+use super::utils::{get_now_unix_time, join_thread};
+
+/// channel ごとにサンプルを溜めておく queue
+///
+/// index は先頭からの通し番号で、discard_before で古いサンプルを捨てても変わらない
 pub struct FftQueue {
-    pop_count: usize, // 累計の index でアクセスするため、いくつ pop したか記録しておく
+    pop_count: usize, // 累計の index でアクセスするため、channel ごとにいくつ pop したか記録しておく
     next_chan: usize, // 次に push するときのチャンネルを持っておく
     queue: Vec<VecDeque<f32>>,
 }
@@ -44,9 +58,24 @@ impl FftQueue {
         }
     }
 
-    pub fn read(&mut self, n_chan: usize) -> Option<f32> {
-        self.pop_count += 1;
-        self.queue[n_chan].pop_front()
+    /// interleave されたサンプルをまとめて push する
+    pub fn extend(&mut self, samples: &[f32]) {
+        for sample in samples {
+            self.push(*sample);
+        }
+    }
+
+    /// index より前のサンプルを全ての channel から捨てる
+    ///
+    /// これから FFT する一番古い窓の先頭を渡せば、メモリを使い続けずに済む
+    pub fn discard_before(&mut self, index: usize) {
+        let count = index
+            .saturating_sub(self.pop_count)
+            .min(self.queue.iter().map(|q| q.len()).min().unwrap_or(0));
+        for q in self.queue.iter_mut() {
+            q.drain(..count);
+        }
+        self.pop_count += count;
     }
 
     pub fn get_n_chan(&self) -> usize {
@@ -55,30 +84,50 @@ impl FftQueue {
 
     pub fn set_buffer(
         &self,
-        buffer: &mut Vec<Complex32>,
+        buffer: &mut [Complex32],
         chan: usize,
         start_index: usize,
         window_size: usize,
     ) {
-        for i in 0..window_size {
-            buffer[i].re = self.queue[chan][i + start_index];
-            buffer[i].im = 0.0;
+        debug_assert!(start_index >= self.pop_count, "window is already discarded");
+        let start = start_index - self.pop_count;
+        let samples = self.queue[chan].range(start..start + window_size);
+        for (value, sample) in buffer.iter_mut().zip(samples) {
+            value.re = *sample;
+            value.im = 0.0;
         }
     }
 }
 
-enum ProcessEvent {
-    End,
-    Exit,
+/// FFT の段階から render_prepare に送るもの
+#[derive(Debug, Clone, Copy)]
+pub enum FftEvent {
+    /// chan の index から始まる窓の、target 番目の target_freqs の bin を index 0 での正弦波の振幅と位相に直したもの
+    Bin {
+        chan: usize,
+        target: usize,
+        index: usize,
+        value: Complex32,
+    },
+    /// index の直前でデータが途切れた。それより前の窓に基づく制御の状態は使えない
+    Discontinuity { index: usize },
+    /// chan の index から始まる窓は FFT せずに飛ばした
+    Skipped { chan: usize, index: usize },
+    /// chan の index から始まる窓は worker が読んでいる間に上書きされたので使えなかった
+    Overrun { chan: usize, index: usize },
 }
 
-enum QueueingEvent {
-    Setup,
-    Enqueue,
+/// worker が FFT し終えた窓の結果。seq は scheduler が窓を渡した順番
+///
+/// 読んでいる間に上書きされた窓は events が FftEvent::Overrun だけになる
+struct Finished {
+    seq: u64,
+    events: Vec<FftEvent>,
 }
 
 // debug 用の関数。plot-${chan}.png に fft の結果を plot する
-fn plot(buffer: &Vec<Complex32>, title_suffix: String) {
+#[allow(dead_code)]
+fn plot(buffer: &[Complex32], title_suffix: String) {
     let x_freq = (0..buffer.len()).collect::<Vec<usize>>();
     let y_db = buffer
         .iter()
@@ -97,7 +146,7 @@ fn plot(buffer: &Vec<Complex32>, title_suffix: String) {
 
     let (y_min, y_max) = y_db
         .iter()
-        .fold((0.0 / 0.0, 0.0 / 0.0), |(m, n), v| (v.min(m), v.max(n)));
+        .fold((f32::NAN, f32::NAN), |(m, n), v| (v.min(m), v.max(n)));
     let mut chart = ChartBuilder::on(&root)
         .caption(caption, font.into_font()) // キャプションのフォントやサイズ
         .margin(10) // 上下左右全ての余白
@@ -117,187 +166,283 @@ fn plot(buffer: &Vec<Complex32>, title_suffix: String) {
     chart.draw_series(line_series).unwrap();
 }
 
-/// sender が drop されるまで終わらない
+/// receiver の送り手が全て drop されたら、溜まっている窓を全て FFT し終えてから終わる
+///
+/// worker は並列に FFT するが、結果は窓を渡した順番に並べ直して sender に送る。
+/// 同じ入力なら毎回同じ順番で Controller に届くので、replay でも同じ決定になる
+///
+/// tx_record があれば、解析の sample rate に変換した音をそこにも送る
 pub fn fft_scheduler_thread_func(
-    receiver: Receiver<f32>,
-    sender: Sender<(usize, usize, Complex32)>,
-    is_stopped: Arc<AtomicBool>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // TODO: WaveFormat を受け取る
-    let chan_count = 2;
-
-    let mut planner = FftPlanner::new();
-    let fft = planner.plan_fft_forward(WINDOW_SIZE);
-    let queue = Arc::new(RwLock::new(FftQueue::new(chan_count)));
-
-    let total_length = Arc::new(AtomicUsize::new(0));
-    let total_length_clone = total_length.clone();
-    let queueing_queue_clone = queue.clone();
-    let (tx_queueing, rx_queueing) = channel::<QueueingEvent>();
-    // TODO: QueueingEvent の channel をわたす
+    config: PipelineConfig,
+    receiver: Receiver<Packet>,
+    sender: Sender<FftEvent>,
+    tx_record: Option<Sender<RecordEvent>>,
+) -> Result<()> {
+    // sliding DFT は窓ごとに計算し直さないので、リングバッファと worker を使わない
+    if config.estimator == Estimator::SlidingDft {
+        return sliding_dft_thread_func(config, receiver, sender, tx_record);
+    }
+
+    let chan_count = config.channels;
+
+    let estimator = BinEstimator::new(&config);
+    // 書き込みは queueing_thread だけが行い、worker はロックを取らずに読む
+    let ring = Arc::new(RingBuffer::new(chan_count, config.buffer_size));
+
+    // 最後に途切れたフレームの index。途切れていなければ 0
+    let discontinuity = Arc::new(AtomicUsize::new(0));
+
+    let queueing_ring_clone = ring.clone();
+    let queueing_discontinuity_clone = discontinuity.clone();
+    let queueing_config = config.clone();
     let queueing_thread = thread::spawn(move || {
         queueing_thread_func(
-            queueing_queue_clone,
-            total_length_clone,
+            queueing_config,
+            queueing_ring_clone,
+            queueing_discontinuity_clone,
             receiver,
-            tx_queueing,
+            tx_record,
         )
     });
 
     // 実際にFFTを実行するスレッドを建てる
-    let (tx_process_event, rx_process_event) = channel::<(usize, ProcessEvent)>();
+    let (tx_process_event, rx_process_event) = channel::<(usize, Option<Finished>)>();
     let mut process_channels = Vec::new();
     let mut process_threads = Vec::new();
-    // TODO: CPU のコア数ぶんだけスレッドをたてるようにする
-    for id in 0..8 {
-        let queue_clone = queue.clone();
-        let fft_clone = fft.clone();
+    for id in 0..config.worker_count {
+        let ring_clone = ring.clone();
+        let config_clone = config.clone();
+        let estimator_clone = estimator.clone();
         let tx_process_event_clone = tx_process_event.clone();
 
-        // (chan, index)をわたして、そのチャンネル、そのインデックスからの FFT を実行させる
-        let (tx_process_target, rx_process_target) = channel::<(usize, usize)>();
+        // (chan, index, seq)をわたして、そのチャンネル、そのインデックスからの FFT を実行させる
+        let (tx_process_target, rx_process_target) = channel::<(usize, usize, u64)>();
         process_channels.push(tx_process_target);
 
-        let sender_clone = sender.clone();
-
         // TODO: CPU を割り当てる
         process_threads.push(thread::spawn(move || {
             fft_process_thread_func(
                 id,
-                fft_clone,
-                queue_clone,
+                config_clone,
+                estimator_clone,
+                ring_clone,
                 tx_process_event_clone,
-                sender_clone,
                 rx_process_target,
             )
         }));
     }
 
+    let mut result = Ok(());
     let mut next_chan = 0;
     let mut next_index = 0;
-    for (id, event) in rx_process_event {
-        if let ProcessEvent::End = event {
-            // もし len が window_size より大きいなら process を開始させる
-            if total_length.load(Relaxed) >= WINDOW_SIZE + next_index {
-                process_channels[id].send((next_chan, next_index)).unwrap();
-
-                next_chan += 1;
-                if next_chan >= chan_count {
+    let mut skipped_windows = 0;
+    let mut last_discontinuity = 0;
+    // 次に渡す窓の順番と、次に sender に送る順番。その間の窓は worker が FFT している
+    let mut next_seq: u64 = 0;
+    let mut next_send: u64 = 0;
+    // 順番が来るまで送らずに持っておく結果
+    let mut finished = BTreeMap::new();
+    // 終了するまで worker が id を送れるように、rx_process_event は最後まで持っておく
+    for (id, done) in rx_process_event.iter() {
+        if let Some(Finished { seq, events }) = done {
+            finished.insert(seq, events);
+        }
+        // 途切れたところをまたぐ窓は使えないので、途切れた後から FFT し直す
+        // queueing_thread は index を書いてからサンプルを書くので、written を先に読む
+        let written = ring.written();
+        let index = discontinuity.load(Acquire);
+        if index > last_discontinuity {
+            last_discontinuity = index;
+            // 途切れた index より前のまだ渡していない窓は使わない
+            let mut events = Vec::new();
+            if next_index < index {
+                while next_index < index {
+                    events.extend((next_chan..chan_count).map(|chan| FftEvent::Skipped {
+                        chan,
+                        index: next_index,
+                    }));
                     next_chan = 0;
-                    next_index += HOP_SIZE;
+                    next_index += config.hop_size;
                 }
+                next_index = index;
             }
+            events.push(FftEvent::Discontinuity { index });
+            finished.insert(next_seq, events);
+            next_seq += 1;
+        }
+
+        // FFT が追いつかずにリングバッファから消えてしまった窓は飛ばす
+        while next_index < ring.oldest() {
+            let skipped = (next_chan..chan_count)
+                .map(|chan| FftEvent::Skipped {
+                    chan,
+                    index: next_index,
+                })
+                .collect();
+            finished.insert(next_seq, skipped);
+            next_seq += 1;
+            next_chan = 0;
+            next_index += config.hop_size;
+            skipped_windows += 1;
         }
-        if is_stopped.load(Relaxed) {
-            println!("end. total_length: {}", total_length.load(Relaxed));
 
-            drop(tx_process_event);
-            for tx in process_channels {
-                drop(tx);
+        // もし len が window_size より大きいなら process を開始させる
+        if written >= config.window_size + next_index {
+            if process_channels[id]
+                .send((next_chan, next_index, next_seq))
+                .is_err()
+            {
+                result = Err(Error::ChannelDisconnected("fft target"));
+                break;
             }
-            queueing_thread.join().unwrap();
-            for th in process_threads {
-                th.join().unwrap();
+            next_seq += 1;
+
+            next_chan += 1;
+            if next_chan >= chan_count {
+                next_chan = 0;
+                next_index += config.hop_size;
+            }
+        }
+
+        // 前の窓が全て揃った結果から順に送る
+        while let Some(events) = finished.remove(&next_send) {
+            next_send += 1;
+            if events.into_iter().any(|event| sender.send(event).is_err()) {
+                result = Err(Error::ChannelDisconnected("fft result"));
+                break;
             }
+        }
+        if result.is_err() {
+            break;
+        }
+
+        // receiver が閉じて queueing_thread が終わり、残りの窓も全て FFT して送り終えたら終了する
+        if queueing_thread.is_finished()
+            && ring.written() < config.window_size + next_index
+            && next_send == next_seq
+        {
             break;
         }
     }
+    println!(
+        "end. total_length: {}, skipped windows: {}",
+        ring.written(),
+        skipped_windows
+    );
+
+    drop(tx_process_event);
+    for tx in process_channels {
+        drop(tx);
+    }
+    // どのスレッドのエラーも返せるように、全て join してから最初のエラーを返す
+    let queueing_result = join_thread(queueing_thread, "fft queueing");
+    for th in process_threads {
+        result = result.and(join_thread(th, "fft process"));
+    }
 
-    Ok(())
+    result.and(queueing_result)
 }
 
 fn queueing_thread_func(
-    queue: Arc<RwLock<FftQueue>>,
-    total_length: Arc<AtomicUsize>,
-    rx: Receiver<f32>,
-    tx: Sender<QueueingEvent>,
-) {
-    let mut temp_queue = VecDeque::<f32>::new();
-    let mut is_initiallized = false;
-    // TODO: ちゃんとする
-    let chan_size = 2;
-
-    for sample in rx {
-        temp_queue.push_back(sample);
-
-        // 初期化していないなら WINDOW_SIZE 分の長さで初期化する
-        if !is_initiallized {
-            if temp_queue.len() >= WINDOW_SIZE * chan_size {
-                println!("want to get queue lock");
-                let mut q = queue.write().unwrap();
-                let enqueue_size = temp_queue.len() / chan_size;
-                while let Some(sample) = temp_queue.pop_front() {
-                    q.push(sample);
-                }
-                is_initiallized = true;
-
-                total_length.fetch_add(enqueue_size, Relaxed);
-                tx.send(QueueingEvent::Setup).unwrap();
-            }
+    config: PipelineConfig,
+    ring: Arc<RingBuffer>,
+    discontinuity: Arc<AtomicUsize>,
+    rx: Receiver<Packet>,
+    tx_record: Option<Sender<RecordEvent>>,
+) -> Result<()> {
+    // device の sample rate から解析の sample rate に変換してからリングバッファに入れる
+    let mut resampler = Resampler::new(
+        config.channels,
+        config.device_sample_rate as u32,
+        config.sample_rate as u32,
+    )?;
+    let mut resampled = Vec::new();
+
+    for packet in rx {
+        // 最初の packet は前がないので途切れとは扱わない
+        let written = ring.written();
+        if packet.flags.is_discontinuity() && written != 0 {
+            discontinuity.store(written, Release);
+            resampler.reset();
         }
-
-        // 初期化済みなら HOP_SIZE を超えると毎回 push できないかチェックする
-        if is_initiallized {
-            if temp_queue.len() > HOP_SIZE * chan_size {
-                if let Ok(mut q) = queue.try_write() {
-                    let enqueue_size = temp_queue.len() / chan_size;
-                    while let Some(sample) = temp_queue.pop_front() {
-                        q.push(sample);
-                    }
-
-                    total_length.fetch_add(enqueue_size, Relaxed);
-                    tx.send(QueueingEvent::Enqueue).unwrap();
-                }
-            }
+        resampled.clear();
+        resampler.process(&packet.samples, &mut resampled);
+        ring.push(&resampled);
+        if let Some(tx_record) = &tx_record {
+            // 記録が止まっても打ち消しは続ける
+            let _ = tx_record.send(RecordEvent::Captured {
+                index: written,
+                samples: resampled.clone(),
+            });
         }
     }
     // capture_thread の tx が drop されると終了する
+
+    Ok(())
 }
 
 fn fft_process_thread_func(
     id: usize,
-    planner: Arc<dyn Fft<f32>>,
-    queue: Arc<RwLock<FftQueue>>,
-    tx: Sender<(usize, ProcessEvent)>,
-    result_sender: Sender<(usize, usize, Complex32)>,
-    rx: Receiver<(usize, usize)>,
-) {
-    let mut buffer = vec![Complex32::new(0.0, 0.0); WINDOW_SIZE];
-
-    let mut lock_time = Vec::new();
+    config: PipelineConfig,
+    estimator: BinEstimator,
+    ring: Arc<RingBuffer>,
+    tx: Sender<(usize, Option<Finished>)>,
+    rx: Receiver<(usize, usize, u64)>,
+) -> Result<()> {
+    let window = Window::new(&config);
+    let mut buffer = vec![Complex32::new(0.0, 0.0); config.window_size];
+    let mut values = Vec::new();
+
+    let mut copy_time = Vec::new();
     let mut fft_time = Vec::new();
-    let mut plot_time = Vec::new();
+    let mut overrun = 0;
 
     loop {
         match rx.recv_timeout(std::time::Duration::from_millis(1)) {
-            Ok((chan, index)) => {
+            Ok((chan, index, seq)) => {
                 let start = get_now_unix_time();
 
-                let q = queue.read().unwrap();
-
-                lock_time.push(get_now_unix_time() - start);
-
-                let start = get_now_unix_time();
-                q.set_buffer(&mut buffer, chan, index, WINDOW_SIZE);
-                // 明示的に read lock を外す
-                drop(q);
-
-                planner.process(&mut buffer);
-
-                fft_time.push(get_now_unix_time() - start);
-
-                // let start = get_now_unix_time();
-
-                // // TODO: ここで FFT の結果に対する処理をする
-                result_sender.send((chan, index, buffer[TARGET_FREQ_INDEX].clone()));
-                // plot(&buffer, format!("{}-{}", chan, index));
-
-                // plot_time.push(get_now_unix_time() - start);
+                let is_valid = ring.set_buffer(&mut buffer, chan, index);
+
+                copy_time.push(get_now_unix_time() - start);
+
+                // 読んでいる間に上書きされた窓は使えない
+                let mut events = Vec::new();
+                if is_valid {
+                    let start = get_now_unix_time();
+                    window.apply(&mut buffer);
+                    estimator.estimate(&mut buffer, &mut values);
+
+                    fft_time.push(get_now_unix_time() - start);
+
+                    // let start = get_now_unix_time();
+
+                    // // TODO: ここで FFT の結果に対する処理をする
+                    events.extend(
+                        values
+                            .iter()
+                            .enumerate()
+                            .map(|(target, value)| FftEvent::Bin {
+                                chan,
+                                target,
+                                index,
+                                value: window.correct(target, index, *value),
+                            }),
+                    );
+                    // plot(&buffer, format!("{}-{}", chan, index));
+
+                    // plot_time.push(get_now_unix_time() - start);
+                } else {
+                    overrun += 1;
+                    events.push(FftEvent::Overrun { chan, index });
+                }
 
-                tx.send((id, ProcessEvent::End)).unwrap();
+                tx.send((id, Some(Finished { seq, events })))
+                    .map_err(|_| Error::ChannelDisconnected("fft event"))?;
             }
             Err(RecvTimeoutError::Timeout) => {
-                tx.send((id, ProcessEvent::End)).unwrap();
+                tx.send((id, None))
+                    .map_err(|_| Error::ChannelDisconnected("fft event"))?;
             }
             Err(RecvTimeoutError::Disconnected) => {
                 break;
@@ -305,29 +450,159 @@ fn fft_process_thread_func(
         }
     }
     println!(
-        "thread_id: {}, lock_time avg: {1: >2}μs, fft_time avg: {2: >3}μs, plot_time avg: {3: >2}μs",
+        "thread_id: {}, copy_time avg: {1: >2}μs, fft_time avg: {2: >3}μs, overrun: {3}",
         id,
-        lock_time.iter().sum::<u128>()
-            / if lock_time.len() == 0 {
+        copy_time.iter().sum::<u128>()
+            / if copy_time.is_empty() {
                 1
             } else {
-                lock_time.len() as u128
+                copy_time.len() as u128
             }
             / 1000,
         fft_time.iter().sum::<u128>()
-            / if fft_time.len() == 0 {
+            / if fft_time.is_empty() {
                 1
             } else {
                 fft_time.len() as u128
             }
             / 1000,
-        plot_time.iter().sum::<u128>()
-            / if plot_time.len() == 0 {
-                1
-            } else {
-                plot_time.len() as u128
-            }
-            / 1000,
-    )
+        overrun,
+    );
     // fft_thread の tx が drop されると終了する
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PacketFlags;
+    use crate::sim::{simulated_pair, Disturbance, SimConfig};
+    use crate::source::AudioSource;
+    use crate::window::WindowFunction;
+    use crate::PipelineOptions;
+
+    /// start から frames フレーム分の、index と channel が分かる値
+    fn frames(start: usize, frames: usize) -> Vec<f32> {
+        (start..start + frames)
+            .flat_map(|n| [n as f32, n as f32 + 0.5])
+            .collect()
+    }
+
+    fn read(queue: &FftQueue, chan: usize, start: usize, len: usize) -> Vec<f32> {
+        let mut buffer = vec![Complex32::new(0.0, 0.0); len];
+        queue.set_buffer(&mut buffer, chan, start, len);
+        buffer.iter().map(|value| value.re).collect()
+    }
+
+    #[test]
+    fn discard_keeps_absolute_indices() {
+        let mut queue = FftQueue::new(2);
+        queue.extend(&frames(0, 10));
+
+        queue.discard_before(4);
+        assert_eq!(read(&queue, 0, 4, 3), vec![4.0, 5.0, 6.0]);
+        assert_eq!(read(&queue, 1, 4, 3), vec![4.5, 5.5, 6.5]);
+
+        // 追加した後も、捨てた後も同じ index で読める
+        queue.extend(&frames(10, 5));
+        queue.discard_before(9);
+        assert_eq!(
+            read(&queue, 0, 9, 6),
+            vec![9.0, 10.0, 11.0, 12.0, 13.0, 14.0]
+        );
+        assert_eq!(read(&queue, 1, 13, 2), vec![13.5, 14.5]);
+
+        // 既に捨てたところより前を渡しても何もしない
+        queue.discard_before(2);
+        assert_eq!(read(&queue, 0, 9, 1), vec![9.0]);
+    }
+
+    #[test]
+    fn discard_beyond_written_keeps_later_samples() {
+        let mut queue = FftQueue::new(2);
+        queue.extend(&frames(0, 4));
+        // まだ届いていないところまで捨てても、書いた分しか捨てない
+        queue.discard_before(6);
+        queue.extend(&frames(4, 4));
+        assert_eq!(read(&queue, 0, 4, 4), vec![4.0, 5.0, 6.0, 7.0]);
+        assert_eq!(read(&queue, 1, 4, 4), vec![4.5, 5.5, 6.5, 7.5]);
+    }
+
+    /// 30 番目の packet に途切れのフラグを付けた 60 packet を FFT の段階に通し、送られてきたものを返す
+    fn events_around_discontinuity(estimator: Estimator) -> (PipelineConfig, Vec<FftEvent>) {
+        let config = crate::test_utils::config(
+            2,
+            PipelineOptions {
+                window: WindowFunction::Hann,
+                estimator,
+                worker_count: 2,
+                ..Default::default()
+            },
+        );
+        let mut sim = SimConfig::new(Disturbance::Tones {
+            freqs: vec![1000.0],
+            amplitude: 0.5,
+        });
+        // sink に書かなくても返せるように、戻ってくる音を先に溜めておく
+        sim.delay = sim.block_frames * 60;
+        sim.flags = vec![(30, PacketFlags(PacketFlags::DATA_DISCONTINUITY))];
+        let (mut source, _sink) = simulated_pair(sim);
+
+        let (tx_packet, rx_packet) = channel();
+        for _ in 0..60 {
+            tx_packet.send(source.read().unwrap().unwrap()).unwrap();
+        }
+        drop(tx_packet);
+
+        let (tx_fft, rx_fft) = channel();
+        fft_scheduler_thread_func(config.clone(), rx_packet, tx_fft, None).unwrap();
+        (config, rx_fft.iter().collect())
+    }
+
+    #[test]
+    fn discontinuity_resets_analysis() {
+        for estimator in [Estimator::Fft, Estimator::SlidingDft] {
+            let (config, events) = events_around_discontinuity(estimator);
+            let reset = events
+                .iter()
+                .position(|event| matches!(event, FftEvent::Discontinuity { .. }))
+                .unwrap();
+            // 途切れたのは 30 packet 分、1440 フレームの直前
+            assert!(matches!(
+                events[reset],
+                FftEvent::Discontinuity { index: 1440 }
+            ));
+
+            let bins = |events: &[FftEvent]| -> Vec<usize> {
+                events
+                    .iter()
+                    .filter_map(|event| match event {
+                        FftEvent::Bin { index, .. } => Some(*index),
+                        FftEvent::Discontinuity { .. } => panic!("{:?}: reset twice", estimator),
+                        FftEvent::Skipped { .. } | FftEvent::Overrun { .. } => None,
+                    })
+                    .collect()
+            };
+            // 途切れる前に渡せなかった窓は、途切れたところより前のものだけを飛ばす
+            assert!(events[..reset].iter().all(|event| match event {
+                FftEvent::Skipped { index, .. } => *index < 1440,
+                _ => !matches!(event, FftEvent::Overrun { .. }),
+            }));
+            // 途切れをまたぐ窓は使わず、途切れた後の窓は途切れたところから始める
+            let before = bins(&events[..reset]);
+            let after = bins(&events[reset + 1..]);
+            assert!(before
+                .iter()
+                .all(|index| index + config.window_size <= 1440));
+            assert_eq!(after.first(), Some(&1440), "{:?}", estimator);
+            assert!(after.iter().all(|index| *index >= 1440));
+            assert_eq!(
+                *after.last().unwrap(),
+                60 * 48 - config.window_size,
+                "{:?}",
+                estimator
+            );
+        }
+    }
 }
@@ -1,111 +1,430 @@
+mod analyze;
 mod capture;
+mod config;
 mod device;
-mod event;
+mod error;
+mod estimator;
 mod fft;
+mod offline;
+mod packet;
+mod record;
 mod render;
 mod render_prepare;
+mod replay;
+mod resample;
+mod ring;
+mod sample_format;
+mod sim;
+mod sink;
+mod sliding_dft;
+mod source;
+mod stop;
+#[cfg(test)]
+mod test_utils;
 mod utils;
+#[cfg(windows)]
+mod wasapi;
+mod wav;
+mod window;
 
-use bindings::Windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
 use capture::CaptureEvent;
+use fft::FftEvent;
 use hound::WavSpec;
-use rustfft::num_complex::Complex32;
-use std::sync::atomic::AtomicBool;
+use record::{RecordEvent, Recorder};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{mpsc, Arc, Mutex};
-use std::{ptr, thread};
+use std::thread;
 
-use utils::{message_to_windows_error, CoUninitializeOnExit};
+use utils::join_thread;
 
 use render::RenderQueue;
-use utils::from_wide_ptr;
+use wav::{WavFileSink, WavFileSource};
+
+pub use analyze::Spectrum;
+pub use config::{CaptureMode, PipelineConfig, PipelineOptions, SessionOptions};
+pub use device::{
+    select_device, DeviceEnumerator, DeviceInfo, DeviceState, Direction, MockEnumerator,
+};
+pub use error::{Error, Result};
+pub use estimator::Estimator;
+pub use offline::{AnalysisEvent, ControlUpdate};
+pub use packet::{Packet, PacketFlags};
+pub use replay::ReplayReport;
+pub use sample_format::SampleFormat;
+pub use sim::{Disturbance, SimConfig, SimReport};
+pub use sink::AudioSink;
+pub use source::AudioSource;
+pub use stop::StopHandle;
+pub use window::WindowFunction;
+
+#[cfg(windows)]
+pub fn wmain(session: SessionOptions, stop: StopHandle) -> Result<u8> {
+    use bindings::Windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+    use std::ptr;
+    use wasapi::capture::DeviceSource;
+    use wasapi::render::DeviceSink;
+    use wasapi::utils::CoUninitializeOnExit;
 
-pub fn wmain() -> windows::Result<u8> {
     unsafe { CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED)? };
     let _com = CoUninitializeOnExit {};
 
-    return do_everything();
+    let capture = session.capture;
+    let source_device = match capture {
+        CaptureMode::Loopback => session.device.clone(),
+        CaptureMode::Microphone => session.capture_device.clone(),
+    };
+    let sink_device = session.device.clone();
+    do_everything(
+        move || DeviceSource::open(capture, source_device.as_deref()),
+        move || DeviceSink::open(sink_device.as_deref()),
+        session,
+        stop,
+    )
+}
+
+/// 仮想的な音響ループを source と sink にして、実機と同じスレッド構成でパイプラインを回す
+pub fn run_simulated(config: SimConfig, session: SessionOptions, stop: StopHandle) -> Result<u8> {
+    let (source, sink) = sim::simulated_pair(config);
+
+    do_everything(move || Ok(source), move || Ok(sink), session, stop)
 }
 
-fn do_everything() -> windows::Result<u8> {
+/// stop が呼ばれるか session.duration が経つまでパイプラインを回す
+///
+/// 終了するときは render をフェードアウトさせてから capture を止め、溜まっているサンプルを FFT し終えてから返す
+fn do_everything<S, K, FS, FK>(
+    open_source: FS,
+    open_sink: FK,
+    session: SessionOptions,
+    stop: StopHandle,
+) -> Result<u8>
+where
+    S: AudioSource,
+    K: AudioSink,
+    FS: FnOnce() -> Result<S> + Send + 'static,
+    FK: FnOnce() -> Result<K> + Send + 'static,
+{
+    // wave format に依らない設定の誤りは、スレッドを立てる前に返す
+    session.pipeline.validate()?;
+
     // capture スレッドの状態をやりとりするチャンネル
     let (tx, rx): (Sender<CaptureEvent>, Receiver<CaptureEvent>) = mpsc::channel();
     // wave format をやりとりするチャンネル
     let (tx_wf, rx_wf): (Sender<WavSpec>, Receiver<WavSpec>) = mpsc::channel();
     // capture したパケットをやりとりするチャンネル
-    let (tx_packet, rx_packet): (Sender<f32>, Receiver<f32>) = mpsc::channel();
+    let (tx_packet, rx_packet): (Sender<Packet>, Receiver<Packet>) = mpsc::channel();
     // fft した結果をやりとりするチャンネル
-    let (tx_fft, rx_fft) = mpsc::channel::<(usize, usize, Complex32)>();
+    let (tx_fft, rx_fft) = mpsc::channel::<FftEvent>();
 
-    let is_stopped = Arc::new(AtomicBool::new(false));
-    let is_stopped_capture = is_stopped.clone();
+    // capture と render は別々に止める
+    let is_stopped_capture = Arc::new(AtomicBool::new(false));
+    let is_stopped_capture_clone = is_stopped_capture.clone();
+    let is_stopped_render = Arc::new(AtomicBool::new(false));
 
-    // TODO: 入力を処理して渡すようにする
+    let stop_capture = stop.clone();
     let capture_thread = thread::spawn(move || {
-        capture::capture_thread_func(tx, tx_wf, tx_packet, is_stopped_capture)
-    });
-
-    let is_stopped_fft = is_stopped.clone();
-    let fft_thread = thread::spawn(move || {
-        fft::fft_scheduler_thread_func(rx_packet, tx_fft, is_stopped_fft).unwrap()
+        stop_capture.stop_on_error(capture::capture_thread_func(
+            open_source,
+            tx,
+            tx_wf,
+            tx_packet,
+            is_stopped_capture_clone,
+        ))
     });
 
     // capture_thread の準備を待つ
     match rx.recv() {
         Ok(CaptureEvent::Start) => {}
-        Ok(e) => {
-            return Err(message_to_windows_error(&format!("{:#?}", e)));
-        }
-        Err(e) => {
-            return Err(message_to_windows_error(&format!("{:#?}", e)));
+        Ok(CaptureEvent::Exit) | Err(_) => {
+            // 準備に失敗したなら capture_thread のエラーを返す
+            join_thread(capture_thread, "capture")?;
+            return Err(Error::ChannelDisconnected("capture event"));
         }
     }
 
     println!("start capture");
 
     // capture_thread の準備ができたら
-    let wf: WavSpec;
-    match rx_wf.recv() {
-        Ok(e) => {
-            wf = e;
+    let wf: WavSpec = match rx_wf.recv() {
+        Ok(e) => e,
+        Err(_) => {
+            join_thread(capture_thread, "capture")?;
+            return Err(Error::ChannelDisconnected("wave format"));
         }
+    };
+
+    let config = match PipelineConfig::new(&wf, &session.pipeline) {
+        Ok(config) => config,
         Err(e) => {
-            return Err(message_to_windows_error(&format!("{:#?}", e)));
+            // capture スレッドだけ動いているので止めてから返す
+            is_stopped_capture.store(true, SeqCst);
+            join_thread(capture_thread, "capture")?;
+            return Err(e);
         }
+    };
+
+    // wave format が決まってから FFT を始める。それまでのサンプルは rx_packet に溜まっている
+    // capture が止まって rx_packet が閉じたら、残りを FFT し終えて終了する
+    // 記録するなら、FFT と render から送られてくる音を一つのスレッドで書き出す
+    let mut record_thread = None;
+    let mut tx_record = None;
+    if let Some(path) = &session.record {
+        let recorder = match Recorder::create(path, config.clone()) {
+            Ok(recorder) => recorder,
+            Err(e) => {
+                is_stopped_capture.store(true, SeqCst);
+                join_thread(capture_thread, "capture")?;
+                return Err(e);
+            }
+        };
+        let (tx, rx) = mpsc::channel::<RecordEvent>();
+        let stop_record = stop.clone();
+        record_thread = Some(thread::spawn(move || {
+            stop_record.stop_on_error(record::record_thread_func(recorder, rx))
+        }));
+        tx_record = Some(tx);
     }
 
-    let render_queue = Arc::new(Mutex::new(RenderQueue::new(wf.channels)));
+    let fft_config = config.clone();
+    let fft_tx_record = tx_record.clone();
+    let stop_fft = stop.clone();
+    let fft_thread = thread::spawn(move || {
+        stop_fft.stop_on_error(fft::fft_scheduler_thread_func(
+            fft_config,
+            rx_packet,
+            tx_fft,
+            fft_tx_record,
+        ))
+    });
+
+    let render_queue = Arc::new(Mutex::new(RenderQueue::new(&config)));
     let prepare_render_queue = render_queue.clone();
-    let is_stopped_render = is_stopped.clone();
+    let is_stopped_render_clone = is_stopped_render.clone();
     let is_silence = Arc::new(AtomicBool::new(false));
     let is_silence_clone = is_silence.clone();
 
+    let analysis_sample_rate = config.sample_rate as u32;
+    let prepare_tx_record = tx_record.clone();
+    let stop_render = stop.clone();
     let render_thread = thread::spawn(move || {
-        render::render_thread_func(render_queue, is_stopped_render, is_silence_clone)
+        stop_render.stop_on_error(render::render_thread_func(
+            open_sink,
+            render_queue,
+            analysis_sample_rate,
+            is_stopped_render_clone,
+            is_silence_clone,
+            tx_record,
+        ))
     });
 
     let render_prepare_thread = thread::spawn(move || {
-        render_prepare::render_prepare_thread_func(rx_fft, prepare_render_queue)
+        render_prepare::render_prepare_thread_func(
+            config,
+            rx_fft,
+            prepare_render_queue,
+            prepare_tx_record,
+        );
+        Ok(())
     });
 
-    let sleep_time = std::time::Duration::from_secs(10);
-    thread::sleep(sleep_time);
+    stop.wait(session.duration);
+    stop.stop();
+
+    // 音がいきなり途切れないように、先に render をフェードアウトさせる
+    is_stopped_render.store(true, SeqCst);
+    let render_result = join_thread(render_thread, "render");
 
-    is_stopped.store(true, std::sync::atomic::Ordering::SeqCst);
+    // capture を止めると rx_packet が閉じ、FFT、render_prepare の順に残りを処理して終了する
+    // どのスレッドのエラーも返せるように、全て join してから最初のエラーを返す
+    is_stopped_capture.store(true, SeqCst);
+    let capture_result = join_thread(capture_thread, "capture");
+    let fft_result = join_thread(fft_thread, "fft");
+    let render_prepare_result = join_thread(render_prepare_thread, "render prepare");
+    // FFT、render、render_prepare が終わると送り手がいなくなり、残りを書き出して終了する
+    let record_result = match record_thread {
+        Some(record_thread) => join_thread(record_thread, "record"),
+        None => Ok(()),
+    };
 
-    capture_thread.join().unwrap()?;
-    render_thread.join().unwrap()?;
-    fft_thread.join().unwrap();
-    render_prepare_thread.join().unwrap();
+    capture_result?;
+    render_result?;
+    fft_result?;
+    render_prepare_result?;
+    record_result?;
 
     Ok(0)
 }
 
-pub fn print_device_list() -> windows::Result<u8> {
+/// render と capture の endpoint の一覧
+#[cfg(windows)]
+pub fn list_devices() -> Result<Vec<DeviceInfo>> {
+    use bindings::Windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+    use std::ptr;
+    use wasapi::device::WasapiEnumerator;
+    use wasapi::utils::CoUninitializeOnExit;
+
     unsafe { CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED)? };
     let _com = CoUninitializeOnExit {};
 
-    let d = device::get_default_device()?;
-    println!("{:#?}", from_wide_ptr(unsafe { d.GetId()?.0 }));
-    return device::get_list_devices();
+    WasapiEnumerator::new()?.devices()
+}
+
+/// input の WAV に対してパイプライン全体を実行し、残差を output の WAV に書き出す
+pub fn process_wav_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    options: &PipelineOptions,
+) -> Result<u8> {
+    let source = WavFileSource::open(input)?;
+
+    offline::process(
+        source,
+        |spec| WavFileSink::create(output, spec.channels, spec.sample_rate),
+        options,
+    )
+}
+
+/// 仮想的な音響ループで frames フレーム分の閉ループ制御を回す
+pub fn simulate(config: SimConfig, options: &PipelineOptions, frames: usize) -> Result<SimReport> {
+    sim::run(config, options, frames)
+}
+
+/// record で記録した WAV を、記録したときの設定で FFT と制御に通し直す
+pub fn replay_recording<P: AsRef<Path>>(recording: P) -> Result<ReplayReport> {
+    replay::replay(recording)
+}
+
+/// input の WAV のスペクトルを、パイプラインと同じ窓の長さで求める
+pub fn analyze_wav_file<P: AsRef<Path>>(input: P, options: &PipelineOptions) -> Result<Spectrum> {
+    let source = WavFileSource::open(input)?;
+
+    analyze::analyze(source, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+    use std::time::Duration;
+
+    /// 止めた順番と、sink に書いた音を残す
+    #[derive(Clone, Default)]
+    struct Log {
+        stopped: Arc<Mutex<Vec<&'static str>>>,
+        written: Arc<Mutex<Vec<f32>>>,
+    }
+
+    struct LoggedSource<S> {
+        source: S,
+        log: Log,
+    }
+
+    impl<S: AudioSource> AudioSource for LoggedSource<S> {
+        fn start(&mut self) -> Result<WavSpec> {
+            self.source.start()
+        }
+
+        fn read(&mut self) -> Result<Option<Packet>> {
+            self.source.read()
+        }
+
+        fn stop(&mut self) -> Result<()> {
+            self.log.stopped.lock().unwrap().push("capture");
+            self.source.stop()
+        }
+    }
+
+    struct LoggedSink<K> {
+        sink: K,
+        log: Log,
+    }
+
+    impl<K: AudioSink> AudioSink for LoggedSink<K> {
+        fn start(&mut self) -> Result<WavSpec> {
+            self.sink.start()
+        }
+
+        fn wait_writable(&mut self) -> Result<usize> {
+            self.sink.wait_writable()
+        }
+
+        fn write(&mut self, samples: &[f32], is_silent: bool) -> Result<()> {
+            let mut written = self.log.written.lock().unwrap();
+            if is_silent {
+                let len = written.len() + samples.len();
+                written.resize(len, 0.0);
+            } else {
+                written.extend_from_slice(samples);
+            }
+            drop(written);
+            self.sink.write(samples, is_silent)
+        }
+
+        fn stop(&mut self) -> Result<()> {
+            self.log.stopped.lock().unwrap().push("render");
+            self.sink.stop()
+        }
+    }
+
+    #[test]
+    fn drains_in_order_and_fades_out() {
+        let path = temp_path("shutdown.wav");
+        let session = SessionOptions {
+            duration: Some(Duration::from_millis(300)),
+            record: Some(path.clone()),
+            ..Default::default()
+        };
+        let (source, sink) = sim::simulated_pair(SimConfig::new(Disturbance::Tones {
+            freqs: vec![1000.0],
+            amplitude: 0.5,
+        }));
+        let log = Log::default();
+        let source = LoggedSource {
+            source,
+            log: log.clone(),
+        };
+        let sink = LoggedSink {
+            sink,
+            log: log.clone(),
+        };
+
+        // どこかのスレッドが終わらなければ、ここで待ち続けずに失敗させる
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let result = do_everything(
+                move || Ok(source),
+                move || Ok(sink),
+                session,
+                StopHandle::new(),
+            );
+            let _ = tx.send(result);
+        });
+        let result = rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("pipeline threads did not finish");
+        assert_eq!(result.unwrap(), 0);
+
+        // 音が途切れないように render を止めてから capture を止める
+        assert_eq!(*log.stopped.lock().unwrap(), vec!["render", "capture"]);
+
+        // 打ち消していた音を 20ms かけて 0 まで小さくする。その間も制御は続くので、振幅は max_amplitude までで抑える
+        let written = log.written.lock().unwrap();
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let fade_samples = 960 * 2;
+        let before = &written[written.len() - 2 * fade_samples..written.len() - fade_samples];
+        assert!(peak(before) > 0.4, "{}", peak(before));
+        let last_block = &written[written.len() - 48 * 2..];
+        assert!(peak(last_block) <= 48.0 / 960.0, "{}", peak(last_block));
+        let last_frame = &written[written.len() - 2..];
+        assert!(peak(last_frame) <= 1.0 / 960.0, "{}", peak(last_frame));
+
+        // FFT と render_prepare が残りを送り終えてから、record が書き終える
+        let sidecar = record::Sidecar::load(&path).unwrap();
+        assert!(sidecar.frames > 0);
+        assert!(!sidecar.updates.is_empty());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+    }
 }
@@ -0,0 +1,400 @@
+use hound::WavSpec;
+use rustfft::num_complex::Complex32;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+
+use super::config::{PipelineConfig, PipelineOptions};
+use super::error::Result;
+use super::estimator::{BinEstimator, Estimator};
+use super::fft::FftQueue;
+use super::render::RenderQueue;
+use super::render_prepare::Controller;
+use super::resample::{OutputResampler, Resampler};
+use super::sink::AudioSink;
+use super::sliding_dft::SlidingDft;
+use super::source::AudioSource;
+use super::window::Window;
+
+/// RenderQueue に反映した Controller の決定
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ControlUpdate {
+    /// 決定の元になった窓の先頭のフレームの index (解析の sample rate)
+    pub index: usize,
+    pub chan: usize,
+    /// target_freqs の何番目の周波数の決定か
+    pub target: usize,
+    pub amplitude: f32,
+    pub angle: f32,
+    /// RenderQueue がこの決定を使い始めたフレームの index (解析の sample rate)
+    pub position: u64,
+}
+
+/// live の解析で、窓ごとの決定とは別に制御の順番を変えたこと。replay で同じ窓を同じ状態の Controller に渡すのに使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnalysisEvent {
+    /// index の直前でデータが途切れたので、Controller をやり直して index から解析し直した
+    Discontinuity { index: usize },
+    /// chan の index から始まる窓は FFT せずに飛ばした。
+    /// FFT が追いつかずにリングバッファから消えたか、途切れた後まで解析を進めたとき
+    Skipped { chan: usize, index: usize },
+    /// chan の index から始まる窓は、worker が読んでいる間に上書きされたので使わなかった
+    Overrun { chan: usize, index: usize },
+}
+
+/// FFT による解析、Controller による制御、RenderQueue による逆位相の音の生成を一つのスレッドで順番に行う
+///
+/// 時刻は解析したサンプル数と生成したサンプル数で数えるので、同じ入力からは毎回同じ結果になる
+pub struct BlockProcessor {
+    config: PipelineConfig,
+    /// 観測したフレームを解析の sample rate に変換する
+    input: Resampler,
+    resampled: Vec<f32>,
+    /// 解析の sample rate で生成した音を device の sample rate に変換する
+    output: OutputResampler,
+    queue: FftQueue,
+    estimator: BinEstimator,
+    window: Window,
+    buffer: Vec<Complex32>,
+    /// estimator が SlidingDft のときは、queue に溜めて窓ごとに求める代わりにこれで bin を求める
+    sliding: Option<SlidingDft>,
+    /// target ごとの bin
+    values: Vec<Complex32>,
+    /// channel と target_freqs ごとに出している音が違うので、制御もそれぞれで行う
+    controllers: Vec<Vec<Controller>>,
+    render_queue: RenderQueue,
+    total_length: usize,
+    next_index: usize,
+    update_count: usize,
+    /// Some なら RenderQueue を更新するたびに内容を残す
+    log: Option<Vec<ControlUpdate>>,
+    /// channel と target_freqs ごとの、記録したときに決定を反映し始めたフレーム。
+    /// replay ではこちらを RenderQueue の代わりに使う
+    recorded: Option<Vec<Vec<VecDeque<u64>>>>,
+    /// replay で、記録したときにデータが途切れた index を古い順に並べる
+    discontinuities: VecDeque<usize>,
+    /// replay で、記録したときに使わなかった窓の (chan, index)
+    dropped: HashSet<(usize, usize)>,
+}
+
+impl BlockProcessor {
+    pub fn new(config: PipelineConfig) -> Result<BlockProcessor> {
+        let device_sample_rate = config.device_sample_rate as u32;
+        let sample_rate = config.sample_rate as u32;
+        let window = Window::new(&config);
+        Ok(BlockProcessor {
+            input: Resampler::new(config.channels, device_sample_rate, sample_rate)?,
+            resampled: Vec::new(),
+            output: OutputResampler::new(config.channels, sample_rate, device_sample_rate)?,
+            queue: FftQueue::new(config.channels),
+            estimator: BinEstimator::new(&config),
+            buffer: vec![Complex32::new(0.0, 0.0); config.window_size],
+            sliding: match config.estimator {
+                Estimator::SlidingDft => Some(SlidingDft::new(&config)?),
+                _ => None,
+            },
+            values: Vec::new(),
+            controllers: (0..config.channels)
+                .map(|_| {
+                    (0..config.target_freqs.len())
+                        .map(|target| Controller::new(&config, &window, target))
+                        .collect()
+                })
+                .collect(),
+            render_queue: RenderQueue::new(&config),
+            window,
+            config,
+            total_length: 0,
+            next_index: 0,
+            update_count: 0,
+            log: None,
+            recorded: None,
+            discontinuities: VecDeque::new(),
+            dropped: HashSet::new(),
+        })
+    }
+
+    /// RenderQueue から device の sample rate で frames フレーム分の逆位相の音を interleave して anti に追加する
+    pub fn generate(&mut self, frames: usize, anti: &mut Vec<f32>) {
+        let render_queue = &mut self.render_queue;
+        self.output.fill(frames, anti, |frames, generated| {
+            for _ in 0..frames {
+                render_queue.next_frame(generated);
+            }
+        });
+    }
+
+    /// 観測した interleave されたフレームを受け取り、揃った窓から順に FFT して RenderQueue を更新する
+    pub fn analyze(&mut self, captured: &[f32]) {
+        self.resampled.clear();
+        self.input.process(captured, &mut self.resampled);
+
+        let window_size = self.config.window_size;
+        if let Some(mut sliding) = self.sliding.take() {
+            let resampled = std::mem::take(&mut self.resampled);
+            for frame in resampled.chunks_exact(self.config.channels) {
+                // live と同じく、途切れたフレームを入れる前にやり直す
+                if self.discontinuities.front() == Some(&sliding.written()) {
+                    self.discontinuities.pop_front();
+                    self.restart(sliding.reset());
+                }
+                if let Some(index) = sliding.push_frame(frame) {
+                    for chan in 0..self.config.channels {
+                        sliding.estimate(chan, &mut self.values);
+                        self.control(index, chan);
+                    }
+                }
+            }
+            self.resampled = resampled;
+            self.sliding = Some(sliding);
+            return;
+        }
+
+        self.queue.extend(&self.resampled);
+        self.total_length += self.resampled.len() / self.config.channels;
+
+        while self.total_length >= window_size + self.next_index {
+            // live では途切れた index より前の窓は使うか飛ばすかしていて、その後は途切れた index から解析し直している
+            if let Some(&index) = self.discontinuities.front() {
+                if self.next_index >= index {
+                    self.discontinuities.pop_front();
+                    self.next_index = index;
+                    self.restart(index);
+                    continue;
+                }
+            }
+            for chan in 0..self.config.channels {
+                if self.dropped.remove(&(chan, self.next_index)) {
+                    continue;
+                }
+                self.queue
+                    .set_buffer(&mut self.buffer, chan, self.next_index, window_size);
+                self.window.apply(&mut self.buffer);
+                self.estimator.estimate(&mut self.buffer, &mut self.values);
+                self.control(self.next_index, chan);
+            }
+            self.next_index += self.config.hop_size;
+        }
+
+        // 次の窓より前のサンプルはもう使わない
+        self.queue.discard_before(self.next_index);
+    }
+
+    /// index の直前でデータが途切れたので、全ての Controller をやり直して音を止める
+    fn restart(&mut self, index: usize) {
+        for (chan, targets) in self.controllers.iter_mut().enumerate() {
+            for (target, controller) in targets.iter_mut().enumerate() {
+                controller.reset(index);
+                self.render_queue.update(chan, target, 0.0, 0.0);
+            }
+        }
+    }
+
+    /// chan の index から始まる窓の target ごとの bin (values) を Controller に渡し、決定を RenderQueue に反映する
+    fn control(&mut self, index: usize, chan: usize) {
+        for (target, value) in self.values.iter().enumerate() {
+            let value = self.window.correct(target, index, *value);
+            let recorded = self
+                .recorded
+                .as_mut()
+                .map(|recorded| &mut recorded[chan][target]);
+            let position = match &recorded {
+                Some(recorded) => match recorded.front() {
+                    Some(position) => *position,
+                    // 記録より先は、記録を終えた後に反映されなかった決定
+                    None => u64::MAX,
+                },
+                None => self.render_queue.position(),
+            };
+            let controller = &mut self.controllers[chan][target];
+            if let Some(decision) = controller.process(index, &value, position) {
+                if let Some(recorded) = recorded {
+                    recorded.pop_front();
+                }
+                self.render_queue
+                    .update(chan, target, decision.amplitude, decision.angle);
+                self.update_count += 1;
+                if let Some(log) = self.log.as_mut() {
+                    log.push(ControlUpdate {
+                        index,
+                        chan,
+                        target,
+                        amplitude: decision.amplitude,
+                        angle: decision.angle,
+                        position,
+                    });
+                }
+            }
+        }
+    }
+
+    /// 決定を反映し始めるフレームを、自分の RenderQueue ではなく記録したときの updates から順に取るようにする
+    ///
+    /// live では render のスレッドがどこまで生成していたかで決まるので、replay で同じ決定をするにはそれを使う必要がある。
+    /// events の途切れと使わなかった窓も、live と同じところで Controller に反映する
+    pub fn follow(&mut self, updates: &[ControlUpdate], events: &[AnalysisEvent]) {
+        let mut recorded =
+            vec![vec![VecDeque::new(); self.config.target_freqs.len()]; self.config.channels];
+        for update in updates {
+            if let Some(positions) = recorded
+                .get_mut(update.chan)
+                .and_then(|targets| targets.get_mut(update.target))
+            {
+                positions.push_back(update.position);
+            }
+        }
+        self.recorded = Some(recorded);
+
+        for event in events {
+            match *event {
+                AnalysisEvent::Discontinuity { index } => self.discontinuities.push_back(index),
+                AnalysisEvent::Skipped { chan, index } | AnalysisEvent::Overrun { chan, index } => {
+                    self.dropped.insert((chan, index));
+                }
+            }
+        }
+    }
+
+    /// これまでに RenderQueue を更新した回数
+    pub fn update_count(&self) -> usize {
+        self.update_count
+    }
+
+    /// これ以降の RenderQueue の更新内容を残すようにする
+    pub fn enable_log(&mut self) {
+        self.log.get_or_insert_with(Vec::new);
+    }
+
+    /// 残しておいた更新内容を取り出す
+    pub fn take_log(&mut self) -> Vec<ControlUpdate> {
+        self.log.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+}
+
+/// source の音に逆位相の音を足した残差を sink に書き出す
+///
+/// 残差は実際にスピーカーから出したときに loopback で聞こえるはずの音なので、それをそのまま解析にも使う
+pub fn process<S, K, F>(mut source: S, open_sink: F, options: &PipelineOptions) -> Result<u8>
+where
+    S: AudioSource,
+    K: AudioSink,
+    F: FnOnce(WavSpec) -> Result<K>,
+{
+    let spec = source.start()?;
+    let config = PipelineConfig::new(&spec, options)?;
+
+    let mut sink = open_sink(spec)?;
+    sink.start()?;
+
+    let mut processor = BlockProcessor::new(config)?;
+    let mut residual = Vec::new();
+    let mut frames = 0;
+    while let Some(input) = source.read()? {
+        residual.clear();
+        processor.generate(input.frames, &mut residual);
+        for (r, sample) in residual.iter_mut().zip(input.samples.iter()) {
+            *r += *sample;
+        }
+
+        processor.analyze(&residual);
+        sink.write(&residual, false)?;
+
+        frames += input.frames;
+    }
+
+    source.stop()?;
+    sink.stop()?;
+
+    println!(
+        "offline: frames: {}, updates: {}",
+        frames,
+        processor.update_count()
+    );
+
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+    use crate::wav::{WavFileSink, WavFileSource};
+    use crate::window::WindowFunction;
+    use hound::{WavReader, WavWriter};
+    use std::f64::consts::PI;
+    use std::path::Path;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// 打ち消す 1020 Hz と、打ち消さない 3000 Hz を足した 2 channel の音
+    fn write_input(path: &Path, frames: usize) {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = WavWriter::create(path, spec).unwrap();
+        for n in 0..frames {
+            let t = n as f64 / SAMPLE_RATE as f64;
+            for chan in 0..2 {
+                let target = 0.5 * (2.0 * PI * 1020.0 * t + chan as f64).cos();
+                writer.write_sample((target + remaining(n)) as f32).unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+    }
+
+    /// 打ち消した後に残るはずの音
+    fn remaining(n: usize) -> f64 {
+        0.1 * (2.0 * PI * 3000.0 * n as f64 / SAMPLE_RATE as f64).sin()
+    }
+
+    fn read(path: &Path) -> Vec<f32> {
+        let mut reader = WavReader::open(path).unwrap();
+        reader.samples::<f32>().map(|s| s.unwrap()).collect()
+    }
+
+    fn run(input: &Path, output: &Path) -> Vec<f32> {
+        let options = PipelineOptions {
+            target_freqs: vec![1020.0],
+            window_milli_second: 20.0,
+            window: WindowFunction::BlackmanHarris,
+            ..Default::default()
+        };
+        let source = WavFileSource::open(input).unwrap();
+        process(
+            source,
+            |spec| WavFileSink::create(output, spec.channels, spec.sample_rate),
+            &options,
+        )
+        .unwrap();
+        read(output)
+    }
+
+    #[test]
+    fn leaves_only_untargeted_sound() {
+        let input = temp_path("offline-input.wav");
+        let output = temp_path("offline-output.wav");
+        write_input(&input, SAMPLE_RATE as usize);
+
+        let residual = run(&input, &output);
+        let original = read(&input);
+        assert_eq!(residual.len(), original.len());
+
+        // 最初の窓が揃うまでは何も出さない
+        assert_eq!(residual[..960 * 2], original[..960 * 2]);
+        // 窓が揃ったら 1020 Hz だけを打ち消す
+        let mut max_error = 0.0f64;
+        for (i, sample) in residual.iter().enumerate().skip(2000 * 2) {
+            max_error = max_error.max((*sample as f64 - remaining(i / 2)).abs());
+        }
+        assert!(max_error < 1e-3, "{}", max_error);
+
+        // 同じ入力なら毎回同じ残差になる
+        assert_eq!(run(&input, &output), residual);
+
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
+}
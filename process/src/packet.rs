@@ -0,0 +1,54 @@
+/// AudioSource が返す、interleave されたフレームのまとまり
+///
+/// capture スレッドから FFT へはサンプルごとではなくこの単位で渡す
+#[derive(Debug, Clone, Default)]
+pub struct Packet {
+    /// interleave されたサンプル
+    pub samples: Vec<f32>,
+    /// samples に入っているフレーム数
+    pub frames: usize,
+    pub flags: PacketFlags,
+    /// 最初のフレームを device が取得した時刻 [100ns]
+    pub timestamp: u64,
+}
+
+impl Packet {
+    /// データが来ていないことを表す、フレームを持たない packet
+    pub fn empty() -> Packet {
+        Packet::default()
+    }
+}
+
+/// device が packet に付けたフラグ。値は WASAPI の AUDCLNT_BUFFERFLAGS_* と同じにしてある
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketFlags(pub u32);
+
+impl PacketFlags {
+    /// 前の packet との間で途切れている
+    pub const DATA_DISCONTINUITY: u32 = 0x1;
+    /// 無音として扱うべき packet
+    pub const SILENT: u32 = 0x2;
+    /// timestamp が正しくない
+    pub const TIMESTAMP_ERROR: u32 = 0x4;
+
+    pub fn contains(self, flag: u32) -> bool {
+        self.0 & flag != 0
+    }
+
+    pub fn is_discontinuity(self) -> bool {
+        self.contains(PacketFlags::DATA_DISCONTINUITY)
+    }
+
+    pub fn is_silent(self) -> bool {
+        self.contains(PacketFlags::SILENT)
+    }
+
+    pub fn is_timestamp_error(self) -> bool {
+        self.contains(PacketFlags::TIMESTAMP_ERROR)
+    }
+}
+
+/// 先頭からのフレーム数を packet の timestamp の単位 [100ns] に直す
+pub fn frames_to_timestamp(position: u64, sample_rate: u32) -> u64 {
+    (position as u128 * 10_000_000 / sample_rate as u128) as u64
+}
@@ -0,0 +1,309 @@
+use hound::{SampleFormat, WavSpec, WavWriter};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+
+use super::config::PipelineConfig;
+use super::error::{Error, Result};
+use super::offline::{AnalysisEvent, ControlUpdate};
+
+/// WAV に並べるトラックの順番。それぞれ PipelineConfig::channels 個の channel を持つ
+const TRACKS: [&str; 2] = ["captured", "anti_noise"];
+
+/// 記録するために各スレッドから送られてくる、解析の sample rate の interleave されたフレーム
+pub enum RecordEvent {
+    /// 解析の sample rate に変換した capture の音。index は先頭のフレームの、capture を始めてからの通し番号
+    Captured { index: usize, samples: Vec<f32> },
+    /// RenderQueue が生成した逆位相の音。render device の channel に振り分ける前のもの
+    ///
+    /// position は先頭のフレームの RenderQueue::position
+    Rendered { position: u64, samples: Vec<f32> },
+    /// Controller の決定と、RenderQueue がそれを使い始めたフレーム
+    Update(ControlUpdate),
+    /// 途切れや使わなかった窓など、Controller に窓を渡す順番を変えたこと
+    Analysis(AnalysisEvent),
+}
+
+/// 記録した WAV と一緒に書き出す JSON の中身
+#[derive(Serialize, Deserialize)]
+pub struct Sidecar {
+    pub config: PipelineConfig,
+    pub tracks: Vec<String>,
+    pub channels_per_track: usize,
+    pub frames: u64,
+    /// live で RenderQueue に反映した決定。replay で同じ決定になるかを確かめるのに使う
+    #[serde(default)]
+    pub updates: Vec<ControlUpdate>,
+    /// live の解析で起きた途切れと使わなかった窓。replay で同じところから Controller をやり直すのに使う
+    #[serde(default)]
+    pub events: Vec<AnalysisEvent>,
+}
+
+impl Sidecar {
+    /// 記録した WAV と同じ名前の .json を読み込む
+    pub fn load<P: AsRef<Path>>(recording: P) -> Result<Sidecar> {
+        let path = sidecar_path(recording.as_ref());
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        serde_json::from_str(&text).map_err(|e| Error::Format(format!("{}: {}", path.display(), e)))
+    }
+
+    /// name のトラックが何番目の channel から始まるか
+    pub fn track_offset(&self, name: &str) -> Option<usize> {
+        let position = self.tracks.iter().position(|track| track == name)?;
+        Some(position * self.channels_per_track)
+    }
+}
+
+fn sidecar_path(recording: &Path) -> PathBuf {
+    recording.with_extension("json")
+}
+
+/// capture の音と逆位相の音をフレームを揃えて一つの WAV に書き出す
+///
+/// WAV の k 番目のフレームは capture の k 番目のフレームで、逆位相の音はそこで聞こえるはずのもの
+/// (PipelineConfig::loop_delay だけ前に生成したもの) を並べる。
+/// loopback でもマイクでも capture した音には逆位相の音が既に足されているので、残差は captured のトラックそのもの
+pub struct Recorder {
+    writer: WavWriter<BufWriter<File>>,
+    path: PathBuf,
+    config: PipelineConfig,
+    /// 逆位相の音が生成されてから capture に聞こえるまでのフレーム数
+    delay: u64,
+    /// WAV の frames 番目のフレームから先の、まだ書いていないサンプル
+    captured: VecDeque<f32>,
+    rendered: VecDeque<f32>,
+    updates: Vec<ControlUpdate>,
+    events: Vec<AnalysisEvent>,
+    frames: u64,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P, config: PipelineConfig) -> Result<Recorder> {
+        let spec = WavSpec {
+            channels: (config.channels * TRACKS.len()) as u16,
+            sample_rate: config.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        Ok(Recorder {
+            writer: WavWriter::create(path.as_ref(), spec)?,
+            path: path.as_ref().to_path_buf(),
+            delay: config.loop_delay().round() as u64,
+            config,
+            captured: VecDeque::new(),
+            rendered: VecDeque::new(),
+            updates: Vec::new(),
+            events: Vec::new(),
+            frames: 0,
+        })
+    }
+
+    pub fn push(&mut self, event: RecordEvent) -> Result<()> {
+        let n_chan = self.config.channels;
+        let frames = self.frames;
+        match event {
+            RecordEvent::Captured { index, samples } => {
+                place(&mut self.captured, frames, index as u64, &samples, n_chan)
+            }
+            RecordEvent::Rendered { position, samples } => place(
+                &mut self.rendered,
+                frames,
+                position + self.delay,
+                &samples,
+                n_chan,
+            ),
+            RecordEvent::Update(update) => {
+                self.updates.push(update);
+                return Ok(());
+            }
+            RecordEvent::Analysis(event) => {
+                self.events.push(event);
+                return Ok(());
+            }
+        }
+        self.write_frames(false)
+    }
+
+    /// 残りを書き出して WAV を閉じ、設定を JSON に書き出す
+    pub fn finish(mut self) -> Result<()> {
+        self.write_frames(true)?;
+        self.writer.finalize()?;
+
+        let channels_per_track = self.config.channels;
+        let sidecar = Sidecar {
+            config: self.config,
+            tracks: TRACKS.iter().map(|track| track.to_string()).collect(),
+            channels_per_track,
+            frames: self.frames,
+            updates: self.updates,
+            events: self.events,
+        };
+        let json = serde_json::to_string_pretty(&sidecar)
+            .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+        std::fs::write(sidecar_path(&self.path), json)?;
+
+        Ok(())
+    }
+
+    /// 両方揃ったフレームを書き出す。flush なら片方しかないフレームも、ない方を無音として書き出す
+    ///
+    /// 片方が 1 秒分より多く先に進んだら、遅れている方は間に合わなかったものとして無音で書き進める
+    fn write_frames(&mut self, flush: bool) -> Result<()> {
+        let n_chan = self.config.channels;
+        let captured_frames = self.captured.len() / n_chan;
+        let rendered_frames = self.rendered.len() / n_chan;
+        let ahead = captured_frames.max(rendered_frames);
+        let frames = if flush {
+            ahead
+        } else {
+            captured_frames
+                .min(rendered_frames)
+                .max(ahead.saturating_sub(self.config.sample_rate))
+        };
+
+        let mut captured = vec![0.0; n_chan];
+        let mut rendered = vec![0.0; n_chan];
+        for _ in 0..frames {
+            for chan in 0..n_chan {
+                captured[chan] = self.captured.pop_front().unwrap_or(0.0);
+                rendered[chan] = self.rendered.pop_front().unwrap_or(0.0);
+            }
+            for sample in captured.iter().chain(rendered.iter()) {
+                self.writer.write_sample(*sample)?;
+            }
+        }
+        self.frames += frames as u64;
+
+        Ok(())
+    }
+}
+
+/// WAV の start 番目のフレームから始まる samples を、frames 番目のフレームから始まる pending に並べる
+///
+/// 間が空いていれば無音で埋め、既に書き出したフレームは捨てる
+fn place(pending: &mut VecDeque<f32>, frames: u64, start: u64, samples: &[f32], n_chan: usize) {
+    let end = frames + (pending.len() / n_chan) as u64;
+    if start > end {
+        pending.resize(pending.len() + (start - end) as usize * n_chan, 0.0);
+    }
+    let skip = (end.saturating_sub(start) as usize * n_chan).min(samples.len());
+    pending.extend(&samples[skip..]);
+}
+
+/// 送られてくるフレームを recorder に記録し続ける。送り手が全ていなくなったら書き終えて終了する
+pub fn record_thread_func(mut recorder: Recorder, rx: Receiver<RecordEvent>) -> Result<()> {
+    for event in rx.iter() {
+        recorder.push(event)?;
+    }
+    recorder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+    use crate::PipelineOptions;
+    use hound::WavReader;
+
+    /// 生成してから 1ms (48 フレーム) 後に聞こえる設定
+    fn config() -> PipelineConfig {
+        crate::test_utils::config(
+            2,
+            PipelineOptions {
+                latency_milli_second: 1.0,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// start から frames フレーム分の、フレームと channel ごとに違う値
+    fn frames(start: u64, frames: usize, sign: f32) -> Vec<f32> {
+        (start..start + frames as u64)
+            .flat_map(|n| [sign * n as f32, sign * (n as f32 + 0.5)])
+            .collect()
+    }
+
+    fn captured(index: u64, n: usize) -> RecordEvent {
+        RecordEvent::Captured {
+            index: index as usize,
+            samples: frames(index, n, 1.0),
+        }
+    }
+
+    fn rendered(position: u64, n: usize) -> RecordEvent {
+        RecordEvent::Rendered {
+            position,
+            samples: frames(position, n, -1.0),
+        }
+    }
+
+    /// WAV のフレームごとの captured, anti_noise
+    fn read_tracks(path: &Path) -> Vec<[Vec<f32>; 2]> {
+        let mut reader = WavReader::open(path).unwrap();
+        let samples: Vec<f32> = reader.samples::<f32>().map(|s| s.unwrap()).collect();
+        samples
+            .chunks_exact(4)
+            .map(|frame| [frame[0..2].to_vec(), frame[2..4].to_vec()])
+            .collect()
+    }
+
+    #[test]
+    fn aligns_tracks_by_frame_index() {
+        let path = temp_path("record-aligned.wav");
+        let mut recorder = Recorder::create(&path, config()).unwrap();
+        // 生成は capture より先に進み、届く単位も揃っていない
+        recorder.push(rendered(0, 300)).unwrap();
+        recorder.push(captured(0, 100)).unwrap();
+        recorder.push(captured(100, 77)).unwrap();
+        recorder.push(rendered(300, 100)).unwrap();
+        recorder.push(captured(177, 300)).unwrap();
+        recorder.finish().unwrap();
+
+        let tracks = read_tracks(&path);
+        assert_eq!(tracks.len(), 477);
+        for (k, [captured, anti]) in tracks.iter().enumerate() {
+            assert_eq!(captured, &frames(k as u64, 1, 1.0), "frame {}", k);
+            // 生成していないところは無音になる
+            let expected = match k.checked_sub(48) {
+                Some(position) if position < 400 => frames(position as u64, 1, -1.0),
+                _ => vec![0.0; 2],
+            };
+            assert_eq!(anti, &expected, "frame {}", k);
+        }
+        let sidecar = Sidecar::load(&path).unwrap();
+        assert_eq!(sidecar.frames, 477);
+        assert_eq!(sidecar.tracks, ["captured", "anti_noise"]);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(sidecar_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn bounds_backlog_when_one_track_stalls() {
+        let path = temp_path("record-stalled.wav");
+        let mut recorder = Recorder::create(&path, config()).unwrap();
+        for block in 0..3000 {
+            recorder.push(captured(block * 48, 48)).unwrap();
+        }
+        // 溜めておくのは 1 秒分と、届いた一回分まで
+        assert!(recorder.captured.len() <= (48000 + 48) * 2);
+        assert!(recorder.frames >= 3000 * 48 - 48000 - 48);
+
+        // 遅れて届いた逆位相の音は、まだ書いていないフレームの分だけ使う
+        let written = recorder.frames;
+        recorder.push(rendered(0, 3000 * 48)).unwrap();
+        recorder.finish().unwrap();
+
+        let tracks = read_tracks(&path);
+        assert_eq!(tracks.len(), 3000 * 48 + 48);
+        assert_eq!(tracks[written as usize - 1][1], vec![0.0; 2]);
+        assert_eq!(tracks[written as usize][1], frames(written - 48, 1, -1.0));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(sidecar_path(&path)).unwrap();
+    }
+}
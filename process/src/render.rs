@@ -1,246 +1,347 @@
-use super::device::get_default_device;
-use super::event::create_event;
-use super::utils::{
-    message_to_windows_error, AudioClientStopOnExit, CoUninitializeOnExit,
-    AUDCLNT_BUFFERFLAGS_SILENT,
-};
-use bindings::Windows::Win32::Media::Audio::CoreAudio::IMMDevice;
-use bindings::Windows::Win32::Media::Audio::CoreAudio::{
-    IAudioClient3, IAudioRenderClient, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
-};
-use bindings::Windows::Win32::Media::Multimedia::WAVE_FORMAT_IEEE_FLOAT;
-use bindings::Windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
-use bindings::Windows::Win32::System::Threading::{WaitForMultipleObjects, WAIT_OBJECT_0};
+use super::config::PipelineConfig;
+use super::error::Result;
+use super::record::RecordEvent;
+use super::resample::OutputResampler;
+use super::sink::AudioSink;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use std::{mem, ptr};
-use windows::Interface;
 
-struct Args {
-    mm_device: IMMDevice,
-    queue: Arc<Mutex<RenderQueue>>,
-    is_stopped: Arc<AtomicBool>,
-    is_silence: Arc<AtomicBool>,
-}
+/// 終了するときに render をフェードアウトさせる時間 [ms]
+const FADE_OUT_MILLI_SECOND: usize = 20;
 
+/// 振幅と位相を変えても、時刻 0 から途切れずに進む cos を出す
+///
+/// 位相は解析の窓と同じく index 0 を基準にするので、Window::correct で直した bin の位相とそのまま比べられる
 struct CosGenerator {
-    time: f64,
-    freq: f64,
-    delta_t: f64,
+    /// 1 サンプルあたりの周期数
+    cycles_per_sample: f64,
     amplitude: f64,
-    angle: f64
+    angle: f64,
 }
 
 impl CosGenerator {
     fn new(freq: f64, fs: f64, amplitude: f64, angle: f64) -> Self {
         CosGenerator {
-            time: 0.0,
-            freq,
-            delta_t: 1.0 / fs,
+            cycles_per_sample: freq / fs,
             amplitude,
-            angle
+            angle,
         }
     }
-    fn next(&mut self) -> f32 {
-        let output = ((self.freq * self.time * std::f64::consts::PI * 2. + self.angle).cos() * self.amplitude) as f32;
-        self.time += self.delta_t;
-        output
+    /// position 番目のサンプル
+    fn sample(&self, position: u64) -> f32 {
+        // 長く動かしても誤差が溜まらないように、周期の端数だけを使う
+        let cycles = (self.cycles_per_sample * position as f64).fract();
+        ((cycles * std::f64::consts::PI * 2. + self.angle).cos() * self.amplitude) as f32
     }
     fn update(&mut self, amplitude: f64, angle: f64) {
-        self.time = 0.0;
         self.amplitude = amplitude;
         self.angle = angle;
     }
 }
 
+/// capture の channel ごとに逆位相の音を、解析の sample rate で生成する
+///
+/// channel ごとに target_freqs の数だけ generator を持ち、それらを足したものを出す
 pub struct RenderQueue {
-    generators: Vec<CosGenerator>
+    generators: Vec<Vec<CosGenerator>>,
+    /// 次に生成するフレームの index
+    position: u64,
 }
 
 impl RenderQueue {
-    pub fn new(n_chan: u16) -> RenderQueue {
+    pub fn new(config: &PipelineConfig) -> RenderQueue {
         let mut generators = Vec::new();
-        for _ in 0..n_chan {
-            generators.push(CosGenerator::new(1.0, 44100.0, 0.0, 0.0));
+        for _ in 0..config.channels {
+            generators.push(
+                config
+                    .target_freqs
+                    .iter()
+                    .map(|freq| {
+                        CosGenerator::new(*freq as f64, config.sample_rate as f64, 0.0, 0.0)
+                    })
+                    .collect(),
+            );
         }
         RenderQueue {
             generators,
+            position: 0,
         }
     }
 
-    pub fn next(&mut self, n_chan: usize) -> f32 {
-        self.generators[n_chan].next()
+    /// 全ての generator を 1 サンプルずつ進めて、capture の channel ごとの 1 フレームを frame に追加する
+    ///
+    /// render device より capture の channel が多いときも、時間は揃えて進める
+    pub fn next_frame(&mut self, frame: &mut Vec<f32>) {
+        let position = self.position;
+        frame.extend(self.generators.iter().map(|generators| {
+            generators
+                .iter()
+                .map(|generator| generator.sample(position))
+                .sum::<f32>()
+        }));
+        self.position += 1;
     }
 
-    pub fn update(&mut self, n_chan: usize, amplitude: f32, angle: f32) {
-        self.generators[n_chan].update(amplitude as f64, angle as f64)
+    /// 次に生成するフレームの index。update した振幅と位相はこのフレームから使われる
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// 次に生成するフレームから、振幅と位相を変える
+    pub fn update(&mut self, n_chan: usize, target: usize, amplitude: f32, angle: f32) {
+        self.generators[n_chan][target].update(amplitude as f64, angle as f64)
     }
 }
 
-pub fn render_thread_func(
+/// capture の channel ごとの frame を、channel_count 個の channel を持つ render device の 1 フレームにして buffer に追加する
+///
+/// capture が mono なら全ての channel に同じ音を出し、capture にない channel は無音にする
+fn map_frame(frame: &[f32], channel_count: usize, buffer: &mut Vec<f32>) {
+    if frame.len() == 1 {
+        buffer.extend(std::iter::repeat_n(frame[0], channel_count));
+        return;
+    }
+    buffer.extend((0..channel_count).map(|n_chan| frame.get(n_chan).copied().unwrap_or(0.0)));
+}
+
+/// open_sink で作った sink に RenderQueue が生成した音を書き込み続ける
+///
+/// sink は COM の初期化などスレッドに紐づく準備が必要なことがあるので、このスレッドの中で作る
+pub fn render_thread_func<S, F>(
+    open_sink: F,
     queue: Arc<Mutex<RenderQueue>>,
+    analysis_sample_rate: u32,
     is_stopped: Arc<AtomicBool>,
     is_silence: Arc<AtomicBool>,
-) -> windows::Result<u8> {
-    unsafe { CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED)? };
-    let _com = CoUninitializeOnExit {};
-
-    // args を作成。TODO: 入力を受け取るようにする
-
-    let default_device = get_default_device()?;
-
-    let args = Args {
-        mm_device: default_device,
+    tx_record: Option<Sender<RecordEvent>>,
+) -> Result<u8>
+where
+    S: AudioSink,
+    F: FnOnce() -> Result<S>,
+{
+    let sink = open_sink()?;
+
+    println!("render: setup sink");
+
+    render(
+        sink,
         queue,
+        analysis_sample_rate,
         is_stopped,
         is_silence,
-    };
-
-    println!("render: setup args");
-
-    render(args)?;
-    Ok(0)
+        tx_record,
+    )
 }
 
-fn render(args: Args) -> windows::Result<u8> {
-    // TODO: https://docs.microsoft.com/en-us/windows-hardware/drivers/audio/low-latency-audio#windows-audio-session-api-wasapi
-    let audio_client: IAudioClient3 = unsafe {
-        let mut audio_client = ptr::null_mut();
-
-        args.mm_device
-            .Activate(&IAudioClient3::IID, 0x17, ptr::null(), &mut audio_client)?;
-        mem::transmute::<_, IAudioClient3>(audio_client)
-    };
-    let _audio_client = AudioClientStopOnExit {
-        client: &audio_client,
-    };
-
-    let wfx = unsafe { audio_client.GetMixFormat()? };
-
-    unsafe { (*wfx).wFormatTag = WAVE_FORMAT_IEEE_FLOAT as u16 };
-    unsafe { (*wfx).cbSize = 0 };
-
-    let blockalign = unsafe { (*wfx).nBlockAlign };
-    let channel_count = unsafe { (*wfx).nChannels };
-
-    unsafe {
-        audio_client.Initialize(
-            AUDCLNT_SHAREMODE_SHARED,
-            AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
-            0,
-            0,
-            wfx,
-            ptr::null(),
-        )?
-    };
-
-    let frames_in_buffer = unsafe { audio_client.GetBufferSize()? };
-
-    let audio_render_client = unsafe {
-        let mut audio_render_client = ptr::null_mut();
-
-        audio_client.GetService(&IAudioRenderClient::IID, &mut audio_render_client)?;
-        mem::transmute::<_, IAudioRenderClient>(audio_render_client)
-    };
-
-    let h_feed_me = create_event()?;
-
-    unsafe {
-        audio_client.SetEventHandle(h_feed_me)?;
-    };
-
-    let _data = unsafe { audio_render_client.GetBuffer(frames_in_buffer)? };
-
-    unsafe { audio_render_client.ReleaseBuffer(frames_in_buffer, AUDCLNT_BUFFERFLAGS_SILENT)? };
-
-    // TODO: AvSetMmThreadCharacteristics を呼ぶか work queue を使うようにする(非オーディオサブシステムによる干渉のムラをなくす？)
-
-    // let task = unsafe {
-    //     winapi::um::avrt::AvSetMmThreadCharacteristicsW(
-    //         OsStr::new("Audio")
-    //             .encode_wide()
-    //             .chain(std::iter::once(0))
-    //             .collect::<Vec<_>>()
-    //             .as_ptr(),
-    //         &mut 0,
-    //     )
-    // };
-    // println!("task.isnull: {}", task.is_null());
-    // if task.is_null() {
-    //     println!("{:#?}", unsafe { GetLastError() })
-    // }
-    // let _task = AvRevertMmThreadCharacteristicsOnExit { h: task };
-
-    unsafe { audio_client.Start()? };
-
-    let mut is_done = false;
+fn render<S: AudioSink>(
+    mut sink: S,
+    queue: Arc<Mutex<RenderQueue>>,
+    analysis_sample_rate: u32,
+    is_stopped: Arc<AtomicBool>,
+    is_silence: Arc<AtomicBool>,
+    tx_record: Option<Sender<RecordEvent>>,
+) -> Result<u8> {
+    let spec = sink.start()?;
+    let channel_count = spec.channels as usize;
+    // RenderQueue は解析の sample rate で生成するので、render device の sample rate に変換する
+    let mut output = OutputResampler::new(channel_count, analysis_sample_rate, spec.sample_rate)?;
+    // フェードアウトは変換する前の音に掛けて、記録する音と出す音を揃える
+    let fade_out_frames = (analysis_sample_rate as usize * FADE_OUT_MILLI_SECOND / 1000).max(1);
+
+    let mut buffer = Vec::new();
+    let mut frame = Vec::new();
+    // 記録する、render device の channel に振り分ける前の音と、その先頭のフレームの RenderQueue::position
+    let mut rendered = Vec::new();
+    let mut rendered_position = 0;
+    // stop event が来てからフェードアウトし終えるまでの残りのフレーム数
+    let mut fade_out_remaining: Option<usize> = None;
     let mut passes = 0;
-    while !is_done {
-        // event をまつ
-        let wait_result = unsafe { WaitForMultipleObjects(1, &h_feed_me, false, u32::MAX) };
-        if wait_result != WAIT_OBJECT_0 {
-            return Err(message_to_windows_error(&format!(
-                "Unexpected WaitForMultipleObjects return value {:#?} on pass {}",
-                wait_result, passes
-            )));
+    while fade_out_remaining != Some(0) {
+        let available_frames = sink.wait_writable()?;
+
+        // main thread から stop event が来たかどうか
+        if fade_out_remaining.is_none() && is_stopped.load(SeqCst) {
+            fade_out_remaining = Some(fade_out_frames);
         }
 
-        let frames_of_padding = unsafe { audio_client.GetCurrentPadding()? };
-        let available_frames = frames_in_buffer - frames_of_padding;
+        if available_frames != 0 {
+            buffer.clear();
+            output.fill(available_frames, &mut buffer, |frames, generated| {
+                let mut q = queue.lock().unwrap();
+                if rendered.is_empty() {
+                    rendered_position = q.position();
+                }
+                for _ in 0..frames {
+                    frame.clear();
+                    q.next_frame(&mut frame);
+                    // フェードアウト中は残りのフレーム数に比例して小さくし、終わったら無音にする
+                    if let Some(remaining) = fade_out_remaining.as_mut() {
+                        let gain = *remaining as f32 / fade_out_frames as f32;
+                        *remaining = remaining.saturating_sub(1);
+                        frame.iter_mut().for_each(|sample| *sample *= gain);
+                    }
+                    map_frame(&frame, channel_count, generated);
+                    if tx_record.is_some() {
+                        rendered.extend_from_slice(&frame);
+                    }
+                }
+            });
+            if let Some(tx_record) = &tx_record {
+                // 記録が止まっても打ち消しは続ける
+                let _ = tx_record.send(RecordEvent::Rendered {
+                    position: rendered_position,
+                    samples: std::mem::take(&mut rendered),
+                });
+            }
 
-        if available_frames == 0 {
-            println!("[ERROR?] Got \"feed me\" event but IAudioClient::GetCurrentPadding reports buffer is full - glitch?");
-            continue;
-            // return Err(message_to_windows_error(&
-            //     "Got \"feed me\" event but IAudioClient::GetCurrentPadding reports buffer is full - glitch?"
-            // ));
+            let is_silent = buffer.is_empty() || is_silence.load(SeqCst);
+            sink.write(&buffer, is_silent)?;
         }
 
-        let data = unsafe { audio_render_client.GetBuffer(available_frames)? };
+        passes += 1;
+    }
 
-        // TODO: data に値を入れる(float32)
-        let mut q = args.queue.lock().unwrap();
-        let data_slice = unsafe {
-            std::slice::from_raw_parts_mut(
-                data,
-                (available_frames * (*wfx).nBlockAlign as u32) as usize,
-            )
-        };
+    sink.stop()?;
 
-        let mut is_exist_sample = false;
-        for frame in data_slice.chunks_exact_mut(blockalign as usize) {
-            for (channel_index, value) in frame
-                .chunks_exact_mut((blockalign / channel_count) as usize)
-                .enumerate()
-            {
-                let sample = q.next(channel_index);
-                let sample_bytes = sample.to_le_bytes();
-                for (bufbyte, cosbyte) in value.iter_mut().zip(sample_bytes.iter()) {
-                    *bufbyte = *cosbyte;
-                }
-                is_exist_sample = true;
-            }
+    println!("render: passes: {}", passes);
+
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PipelineOptions;
+    use hound::{SampleFormat, WavSpec};
+    use std::sync::mpsc::channel;
+
+    /// 書き込まれた音を written に残すだけの sink
+    struct VecSink {
+        written: Arc<Mutex<Vec<f32>>>,
+    }
+
+    impl AudioSink for VecSink {
+        fn start(&mut self) -> Result<WavSpec> {
+            Ok(WavSpec {
+                channels: 2,
+                sample_rate: 48000,
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+            })
         }
 
-        let flag = if !is_exist_sample || args.is_silence.load(SeqCst) {
-            AUDCLNT_BUFFERFLAGS_SILENT
-        } else {
-            0
-        };
+        fn wait_writable(&mut self) -> Result<usize> {
+            Ok(48)
+        }
 
-        // TODO: data に値を入れたら AUDCLNT_BUFFERFLAGS_SILENT を 0 にする
-        unsafe { audio_render_client.ReleaseBuffer(available_frames, flag)? };
+        fn write(&mut self, samples: &[f32], _is_silent: bool) -> Result<()> {
+            self.written.lock().unwrap().extend_from_slice(samples);
+            Ok(())
+        }
 
-        // main thread から stop event が来たかどうか
-        let is_stopped = args.is_stopped.load(SeqCst);
-        if is_stopped {
-            is_done = true;
+        fn stop(&mut self) -> Result<()> {
+            Ok(())
         }
-        passes += 1;
     }
 
-    // TODO: ここに終了処理
+    #[test]
+    fn sums_targets_on_one_clock() {
+        let config = crate::test_utils::config(
+            2,
+            PipelineOptions {
+                target_freqs: vec![1000.0, 3020.0],
+                window_milli_second: 20.0,
+                ..Default::default()
+            },
+        );
+        let mut queue = RenderQueue::new(&config);
+        queue.update(0, 0, 0.3, 0.5);
+        queue.update(0, 1, 0.2, -1.0);
+        queue.update(1, 1, 0.4, 2.0);
+
+        let expected = |freq: f64, amplitude: f64, angle: f64, position: u64| {
+            let phase = 2.0 * std::f64::consts::PI * freq * position as f64 / 48000.0 + angle;
+            amplitude * phase.cos()
+        };
+        let mut frame = Vec::new();
+        for position in 0..200 {
+            // 途中で変えても、位相は時刻 0 を基準にしたまま続ける
+            if position == 77 {
+                assert_eq!(queue.position(), 77);
+                queue.update(0, 1, 0.1, 1.5);
+            }
+            frame.clear();
+            queue.next_frame(&mut frame);
+
+            let (amplitude, angle) = if position < 77 {
+                (0.2, -1.0)
+            } else {
+                (0.1, 1.5)
+            };
+            let left =
+                expected(1000.0, 0.3, 0.5, position) + expected(3020.0, amplitude, angle, position);
+            let right = expected(3020.0, 0.4, 2.0, position);
+            assert_eq!(frame.len(), 2);
+            assert!((frame[0] as f64 - left).abs() < 1e-5, "{}", position);
+            assert!((frame[1] as f64 - right).abs() < 1e-5, "{}", position);
+        }
+        assert_eq!(queue.position(), 200);
+    }
+
+    #[test]
+    fn maps_capture_channels_to_device() {
+        let mut buffer = Vec::new();
+        map_frame(&[0.5], 2, &mut buffer);
+        map_frame(&[0.1, 0.2], 3, &mut buffer);
+        map_frame(&[0.1, 0.2, 0.3], 2, &mut buffer);
+        assert_eq!(buffer, vec![0.5, 0.5, 0.1, 0.2, 0.0, 0.1, 0.2]);
+    }
 
-    Ok(0)
+    #[test]
+    fn records_faded_out_samples() {
+        let config = crate::test_utils::config(
+            2,
+            PipelineOptions {
+                target_freqs: vec![1000.0],
+                ..Default::default()
+            },
+        );
+        let mut queue = RenderQueue::new(&config);
+        queue.update(0, 0, 0.5, 0.0);
+        queue.update(1, 0, 0.5, 1.0);
+
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let sink = VecSink {
+            written: written.clone(),
+        };
+        let (tx_record, rx_record) = channel();
+        // 始めから止まっているので、最初のフレームからフェードアウトする
+        render(
+            sink,
+            Arc::new(Mutex::new(queue)),
+            48000,
+            Arc::new(AtomicBool::new(true)),
+            Arc::new(AtomicBool::new(false)),
+            Some(tx_record),
+        )
+        .unwrap();
+
+        let recorded: Vec<f32> = rx_record
+            .iter()
+            .flat_map(|event| match event {
+                RecordEvent::Rendered { samples, .. } => samples,
+                _ => Vec::new(),
+            })
+            .collect();
+        let written = written.lock().unwrap();
+        // 20ms 分だけ出して終わる
+        assert_eq!(written.len(), 960 * 2);
+        // 記録する音は、実際に出したフェードアウト中の音と同じ
+        assert_eq!(recorded, *written);
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak(&written[..96]) > 0.4);
+        assert!(peak(&written[written.len() - 96..]) <= 0.5 * 48.0 / 960.0);
+    }
 }
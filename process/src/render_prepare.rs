@@ -1,64 +1,242 @@
-use plotters::prelude::*;
-use rustfft::{num_complex::Complex32};
-use std::sync::{mpsc::Receiver, Arc, Mutex};
+use rustfft::num_complex::Complex32;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::{
+    mpsc::{Receiver, Sender},
+    Arc, Mutex,
+};
 
+use super::config::PipelineConfig;
+use super::fft::FftEvent;
+use super::offline::{AnalysisEvent, ControlUpdate};
+use super::record::RecordEvent;
 use super::render::RenderQueue;
-use super::utils::{WINDOW_SIZE, WINDOW_SIZE_MILLI_SECOND, get_now_milli_unix_time};
+use super::window::Window;
 
+/// fft_receiver から来る窓の bin を Controller に渡し、決定を render_queue に反映する
+///
+/// Controller の時刻は実時間ではなく、窓の index と RenderQueue が生成したフレーム数で数えるので、
+/// 同じ順番で同じ窓が来れば replay でも同じ決定になる。tx_record があれば決定をそこにも送る
 pub fn render_prepare_thread_func(
-    fft_receiver: Receiver<(usize, usize, Complex32)>,
+    config: PipelineConfig,
+    fft_receiver: Receiver<FftEvent>,
     render_queue: Arc<Mutex<RenderQueue>>,
+    tx_record: Option<Sender<RecordEvent>>,
 ) {
-    let mut last_check_index = 0;
+    // channel と target_freqs ごとに出している音が違うので、制御もそれぞれで行う
+    let window = Window::new(&config);
+    let mut controllers: Vec<Vec<Controller>> = (0..config.channels)
+        .map(|_| {
+            (0..config.target_freqs.len())
+                .map(|target| Controller::new(&config, &window, target))
+                .collect()
+        })
+        .collect();
 
-    let mut last_update_milli_second = 0;
-    let buffer_milli_second = 1;
-    let mut amplitude = 0.0;
-    let mut angle = 0.0;
-
-    // TODO: log 用、消す
-    let mut log_amplitude_diff_vec = vec![];
-    let mut log_angle_diff_vec = vec![];
-    let mut log_original_amplitude_vec = vec![];
     let mut count = (0, 0);
 
-    for (chan, index, fft_result) in fft_receiver {
+    // 記録が止まっても打ち消しは続ける
+    let record = |event: RecordEvent| {
+        if let Some(tx_record) = &tx_record {
+            let _ = tx_record.send(event);
+        }
+    };
+
+    for event in fft_receiver {
+        let (chan, target, index, fft_result) = match event {
+            FftEvent::Bin {
+                chan,
+                target,
+                index,
+                value,
+            } => (chan, target, index, value),
+            FftEvent::Discontinuity { index } => {
+                // 途切れる前の推定は使えないので、音を止めて推定し直す
+                let mut q = render_queue.lock().unwrap();
+                for (chan, targets) in controllers.iter_mut().enumerate() {
+                    for (target, controller) in targets.iter_mut().enumerate() {
+                        controller.reset(index);
+                        q.update(chan, target, 0.0, 0.0);
+                    }
+                }
+                record(RecordEvent::Analysis(AnalysisEvent::Discontinuity {
+                    index,
+                }));
+                continue;
+            }
+            // 使わなかった窓は Controller に渡さないので、replay でも同じ窓を飛ばせるように記録だけする
+            FftEvent::Skipped { chan, index } => {
+                record(RecordEvent::Analysis(AnalysisEvent::Skipped {
+                    chan,
+                    index,
+                }));
+                continue;
+            }
+            FftEvent::Overrun { chan, index } => {
+                record(RecordEvent::Analysis(AnalysisEvent::Overrun {
+                    chan,
+                    index,
+                }));
+                continue;
+            }
+        };
         count.0 += 1;
-        // TODO: 何らかの方法で iFFT するかどうか決めて、しないなら continue
-        // 今は全てiFFTしてる
-        if last_check_index != 0 && index != WINDOW_SIZE + last_check_index {
-            continue;
+
+        // 決定を反映し始めるフレームが分かるように、判断してから反映するまでロックを取っておく
+        let mut q = render_queue.lock().unwrap();
+        let position = q.position();
+        if let Some(decision) = controllers[chan][target].process(index, &fft_result, position) {
+            count.1 += 1;
+
+            q.update(chan, target, decision.amplitude, decision.angle);
+            drop(q);
+
+            record(RecordEvent::Update(ControlUpdate {
+                index,
+                chan,
+                target,
+                amplitude: decision.amplitude,
+                angle: decision.angle,
+                position,
+            }));
         }
-        last_check_index = index;
-        count.1 += 1;
+    }
+    println!("render_prepare: count: {:#?}", &count);
+}
+
+/// Controller が決めた RenderQueue の更新内容
+#[derive(Debug, Clone, Copy)]
+pub struct ControlDecision {
+    pub amplitude: f32,
+    pub angle: f32,
+}
+
+/// FFT の結果から、逆位相の音を出すための振幅と位相を決める
+///
+/// 時刻は窓の index と RenderQueue が生成したフレーム数で数えるので、実時間で動かしても replay でも同じように動く。
+/// 窓の途中で出している音が変わっても、それぞれの音が窓に占める分を差し引くので、hop ごとに判断できる
+pub struct Controller {
+    hop_size: usize,
+    /// 生成した音が解析されるまでに遅れる分、target_freq の位相が進む量 [rad]
+    delay_angle: f32,
+    /// 生成した音が解析されるまでに遅れるフレーム数
+    delay_frames: u64,
+    /// Window::partial_responses
+    partial: Vec<Complex32>,
+    /// 窓に入ってくる自分の音。聞こえ始めるフレームと、index 0 での振幅と位相を表す複素数を古い順に並べる
+    history: VecDeque<(u64, Complex32)>,
+    last_check_index: usize,
+    /// これより前から始まる窓の結果は使わない
+    first_valid_index: usize,
+    amplitude_gain: f32,
+    angle_gain: f32,
+    max_amplitude: f32,
+    amplitude: f32,
+    angle: f32,
+}
 
-        let now = get_now_milli_unix_time();
-        if now <= buffer_milli_second + WINDOW_SIZE_MILLI_SECOND as u128 + last_update_milli_second {
-            // 出してるつもりの音か分からないため continue
-            continue;
+impl Controller {
+    pub fn new(config: &PipelineConfig, window: &Window, target: usize) -> Controller {
+        // 長い遅れでも誤差が出ないように、周期の端数だけを使う
+        let delay_cycles = (config.target_freqs[target] as f64 * config.loop_delay()
+            / config.sample_rate as f64)
+            .fract();
+        Controller {
+            hop_size: config.hop_size,
+            delay_angle: (2.0 * std::f64::consts::PI * delay_cycles) as f32,
+            delay_frames: config.loop_delay().round() as u64,
+            partial: window.partial_responses(target),
+            history: silent_history(),
+            last_check_index: 0,
+            first_valid_index: 0,
+            amplitude_gain: config.amplitude_gain,
+            angle_gain: config.angle_gain,
+            max_amplitude: config.max_amplitude,
+            amplitude: 0.0,
+            angle: 0.0,
         }
+    }
 
-        // 位相と振幅のずれを検出
-        let (original_amplitude, original_angle) = diff(&fft_result, amplitude, angle);
-        
-        // TODO: 消す
-        log_amplitude_diff_vec.push(original_amplitude - amplitude);
-        log_angle_diff_vec.push(angle - original_angle);
-        log_original_amplitude_vec.push(original_amplitude);
+    /// データが途切れたときに、index より前の窓に基づく状態を捨てて最初からやり直す
+    pub fn reset(&mut self, index: usize) {
+        self.last_check_index = 0;
+        self.first_valid_index = index;
+        self.history = silent_history();
+        self.amplitude = 0.0;
+        self.angle = 0.0;
+    }
 
-        amplitude = original_amplitude;
-        let angle_diff = angle - original_angle;
-        // angle は pi だけ位相が違うようにフィードバック制御したい
-        angle += std::f32::consts::PI - angle_diff;
+    /// index から始まる窓の FFT 結果を受け取り、RenderQueue を更新すべきなら更新内容を返す
+    ///
+    /// position は、今決定を反映したら RenderQueue がそれを使い始めるフレーム
+    pub fn process(
+        &mut self,
+        index: usize,
+        fft_result: &Complex32,
+        position: u64,
+    ) -> Option<ControlDecision> {
+        // 途切れる前のデータを含む窓
+        if index < self.first_valid_index {
+            return None;
+        }
 
+        // hop ごとに一度だけ判断する
+        if self.last_check_index != 0 && index < self.hop_size + self.last_check_index {
+            return None;
+        }
+        self.last_check_index = index;
+
+        // 位相と振幅のずれを検出。自分の音は遅れて届くので、その分だけ位相を戻して比べる
+        let heard_angle = self.angle - self.delay_angle;
+        let heard = self.heard(index);
+        let (original_amplitude, original_angle) = diff(fft_result, heard.norm(), heard.arg());
+
+        let amplitude_diff = original_amplitude - self.amplitude;
+        let angle_diff = heard_angle - original_angle;
+
+        // gain が 1 なら推定した元の振幅をそのまま使う。出せる音には上限がある
+        self.amplitude =
+            (self.amplitude + self.amplitude_gain * amplitude_diff).min(self.max_amplitude);
+        // angle は pi だけ位相が違うようにフィードバック制御したい。遠回りしないように (-pi, pi] で考える
+        self.angle = wrap_angle(self.angle + self.angle_gain * wrap_angle(PI - angle_diff));
 
-        render_queue.lock().unwrap().update(chan, amplitude, angle);
-        last_update_milli_second = now;
+        // 記録より先の replay では position が u64::MAX になるので溢れないようにする。同じところから反映する前の決定は上書きされる
+        let start = position.saturating_add(self.delay_frames);
+        while self.history.len() > 1 && self.history.back().is_some_and(|(s, _)| *s >= start) {
+            self.history.pop_back();
+        }
+        self.history.push_back((
+            start,
+            Complex32::from_polar(self.amplitude, self.angle - self.delay_angle),
+        ));
+
+        Some(ControlDecision {
+            amplitude: self.amplitude,
+            angle: self.angle,
+        })
+    }
+
+    /// index から始まる窓に入っている自分の音を、correct した bin の値と同じ単位で返す
+    fn heard(&mut self, index: usize) -> Complex32 {
+        // 窓より前に終わった音はもう使わない
+        while self.history.len() > 1 && self.history[1].0 <= index as u64 {
+            self.history.pop_front();
+        }
+
+        let size = self.partial.len() - 1;
+        let offset = |frame: u64| frame.saturating_sub(index as u64).min(size as u64) as usize;
+        let mut heard = Complex32::new(0.0, 0.0);
+        for (i, (start, value)) in self.history.iter().enumerate() {
+            let end = self.history.get(i + 1).map_or(u64::MAX, |(end, _)| *end);
+            heard += value * (self.partial[offset(end)] - self.partial[offset(*start)]);
+        }
+        heard
     }
-    println!("render_prepare: count: {:#?}", &count);
-    plot(&log_amplitude_diff_vec, "log_amplitude_diff_vec".to_string());
-    plot(&log_angle_diff_vec, "log_angle_diff_vec".to_string());
-    plot(&log_original_amplitude_vec, "log_original_amplitude_vec".to_string());
+}
+
+/// 何も出していない状態の Controller::history
+fn silent_history() -> VecDeque<(u64, Complex32)> {
+    VecDeque::from([(0, Complex32::new(0.0, 0.0))])
 }
 
 /// 合成後の複素数と自分が加えた振幅、位相を受け取って元の振幅、位相を取得
@@ -70,43 +248,18 @@ fn diff(result: &Complex32, add_amplitude: f32, add_angle: f32) -> (f32, f32) {
     let sin_diff = result_amplitude * result_angle.sin() - add_amplitude * add_angle.sin();
     let cos_diff = result_amplitude * result_angle.cos() - add_amplitude * add_angle.cos();
     let original_amplitude = (sin_diff.powi(2) + cos_diff.powi(2)).powf(0.5);
-    let original_angle = (sin_diff / cos_diff).atan();
+    // atan では cos_diff が負のときに pi だけずれるので、象限まで区別する
+    let original_angle = sin_diff.atan2(cos_diff);
 
     (original_amplitude, original_angle)
 }
 
-fn plot(buffer: &Vec<f32>, title_suffix: String) {
-    let x_freq = (0..buffer.len()).collect::<Vec<usize>>();
-    let y_db = buffer.iter().map(|v| *v).collect::<Vec<f32>>();
-
-    let image_width = 1080;
-    let image_height = 720;
-    let filename = format!("plot-{}.png", title_suffix);
-    // 描画先を指定。画像出力する場合はBitMapBackend
-    let root = BitMapBackend::new(&filename, (image_width, image_height)).into_drawing_area();
-    root.fill(&WHITE).unwrap();
-
-    let caption = "Sample Plot";
-    let font = ("sans-serif", 20);
-
-    let (y_min, y_max) = y_db
-        .iter()
-        .fold((0.0 / 0.0, 0.0 / 0.0), |(m, n), v| (v.min(m), v.max(n)));
-    let mut chart = ChartBuilder::on(&root)
-        .caption(caption, font.into_font()) // キャプションのフォントやサイズ
-        .margin(10) // 上下左右全ての余白
-        .x_label_area_size(16) // x軸ラベル部分の余白
-        .y_label_area_size(42) // y軸ラベル部分の余白
-        .build_cartesian_2d(
-            // x軸とy軸の数値の範囲を指定する
-            *x_freq.first().unwrap()..*x_freq.last().unwrap(), // x軸の範囲
-            y_min..y_max,                                      // y軸の範囲
-        )
-        .unwrap();
-
-    chart.configure_mesh().draw().unwrap();
-
-    // 折れ線グラフの定義＆描画
-    let line_series = LineSeries::new(x_freq.iter().zip(y_db.iter()).map(|(x, y)| (*x, *y)), &RED);
-    chart.draw_series(line_series).unwrap();
+/// angle を (-pi, pi] に収める
+fn wrap_angle(angle: f32) -> f32 {
+    let wrapped = (angle + PI).rem_euclid(2.0 * PI) - PI;
+    if wrapped == -PI {
+        PI
+    } else {
+        wrapped
+    }
 }
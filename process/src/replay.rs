@@ -0,0 +1,182 @@
+use std::path::Path;
+
+use super::error::{Error, Result};
+use super::offline::{BlockProcessor, ControlUpdate};
+use super::record::Sidecar;
+use super::source::AudioSource;
+use super::wav::WavFileSource;
+
+/// 記録を再生した結果
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+    /// 再生した captured のフレーム数
+    pub frames: usize,
+    /// Controller が RenderQueue に反映した決定。同じ記録と同じコードからは毎回同じになる
+    pub updates: Vec<ControlUpdate>,
+    /// 記録したときに live で反映した決定。決定を記録する前の記録なら空
+    pub recorded: Vec<ControlUpdate>,
+}
+
+impl ReplayReport {
+    /// updates 全体の FNV-1a ハッシュ。二つの実行の決定が同じかどうかを手早く比べるのに使う
+    pub fn digest(&self) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut feed = |value: u64| {
+            for byte in value.to_le_bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+        for update in self.updates.iter() {
+            feed(update.index as u64);
+            feed(update.chan as u64);
+            feed(update.target as u64);
+            feed(update.amplitude.to_bits() as u64);
+            feed(update.angle.to_bits() as u64);
+            feed(update.position);
+        }
+        hash
+    }
+
+    /// replay の決定が live の決定と全て同じか
+    pub fn matches_recording(&self) -> bool {
+        self.updates == self.recorded
+    }
+}
+
+/// record で記録した WAV の captured のトラックを、記録したときの設定で FFT と制御に通す
+///
+/// 時刻は実時間ではなく再生したサンプル数で数え、FFT と制御は一つのスレッドで順番に行うので、実時間より速く毎回同じ結果になる。
+/// 決定を反映し始めたフレームは記録したときのものを使うので、live と同じ音が出ていたものとして制御する
+/// live で途切れてやり直したところと使わなかった窓も記録したとおりに扱うので、同じ窓が同じ状態の Controller に渡る
+pub fn replay<P: AsRef<Path>>(recording: P) -> Result<ReplayReport> {
+    let sidecar = Sidecar::load(recording.as_ref())?;
+    let offset = sidecar
+        .track_offset("captured")
+        .ok_or_else(|| Error::Format("recording has no captured track".to_string()))?;
+    let n_chan = sidecar.channels_per_track;
+
+    let mut source = WavFileSource::open(recording)?;
+    let spec = source.start()?;
+    let total_channels = sidecar.tracks.len() * n_chan;
+    if spec.channels as usize != total_channels
+        || spec.sample_rate as usize != sidecar.config.sample_rate
+    {
+        return Err(Error::Format(format!(
+            "recording does not match its sidecar. channels: {} (expected {}), sample rate: {} (expected {})",
+            spec.channels, total_channels, spec.sample_rate, sidecar.config.sample_rate
+        )));
+    }
+
+    // 記録した captured は既に解析の sample rate になっている
+    let mut config = sidecar.config;
+    config.device_sample_rate = config.sample_rate;
+    let mut processor = BlockProcessor::new(config)?;
+    processor.enable_log();
+    processor.follow(&sidecar.updates, &sidecar.events);
+
+    let mut captured = Vec::new();
+    let mut frames = 0;
+    while let Some(packet) = source.read()? {
+        captured.clear();
+        for frame in packet.samples.chunks_exact(total_channels) {
+            captured.extend_from_slice(&frame[offset..offset + n_chan]);
+        }
+        processor.analyze(&captured);
+        frames += packet.frames;
+    }
+    source.stop()?;
+
+    Ok(ReplayReport {
+        frames,
+        updates: processor.take_log(),
+        recorded: sidecar.updates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+    use crate::{
+        AnalysisEvent, Disturbance, Estimator, PacketFlags, PipelineOptions, SessionOptions,
+        SimConfig, StopHandle,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn reproduces_live_decisions() {
+        let path = temp_path("replay.wav");
+        let session = SessionOptions {
+            duration: Some(Duration::from_millis(300)),
+            record: Some(path.clone()),
+            pipeline: PipelineOptions {
+                target_freqs: vec![1020.0],
+                window_milli_second: 20.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let config = SimConfig::new(Disturbance::Tones {
+            freqs: vec![1020.0],
+            amplitude: 0.5,
+        });
+        crate::run_simulated(config, session, StopHandle::new()).unwrap();
+
+        let report = replay(&path).unwrap();
+        let again = replay(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+
+        assert!(!report.recorded.is_empty());
+        assert_eq!(report.updates, report.recorded);
+        assert!(report.matches_recording());
+        assert_eq!(report.digest(), again.digest());
+    }
+
+    #[test]
+    fn restarts_where_live_restarted() {
+        for estimator in [Estimator::Fft, Estimator::SlidingDft] {
+            let path = temp_path("replay-discontinuity.wav");
+            let session = SessionOptions {
+                duration: Some(Duration::from_millis(300)),
+                record: Some(path.clone()),
+                pipeline: PipelineOptions {
+                    target_freqs: vec![1020.0],
+                    window_milli_second: 20.0,
+                    estimator,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut config = SimConfig::new(Disturbance::Tones {
+                freqs: vec![1020.0],
+                amplitude: 0.5,
+            });
+            // 1 packet は 1ms なので、制御が落ち着き始めた 100ms で途切れさせる
+            config.flags = vec![(100, PacketFlags(PacketFlags::DATA_DISCONTINUITY))];
+            crate::run_simulated(config, session, StopHandle::new()).unwrap();
+
+            let sidecar = Sidecar::load(&path).unwrap();
+            let report = replay(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            std::fs::remove_file(path.with_extension("json")).unwrap();
+
+            let restarted = sidecar
+                .events
+                .iter()
+                .filter_map(|event| match event {
+                    AnalysisEvent::Discontinuity { index } => Some(*index),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(restarted.len(), 1, "{:?}", estimator);
+            // 途切れた後の窓は途切れた index から始まる
+            assert!(report
+                .recorded
+                .iter()
+                .any(|update| update.index == restarted[0]));
+            assert!(report.matches_recording(), "{:?}", estimator);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use super::error::{Error, Result};
+
+/// 一つの phase あたりの係数の数。多いほど遷移帯域が狭くなるが遅延も増える
+const TAPS_PER_PHASE: usize = 32;
+/// これより大きい up / down の比は係数表が大きくなりすぎるので扱わない
+const MAX_PHASES: usize = 4096;
+
+/// 窓付き sinc の polyphase filter による sample rate の変換
+///
+/// interleave されたフレームを少しずつ渡しても、まとめて渡したときと同じ結果になる
+pub struct Resampler {
+    channels: usize,
+    up: usize,
+    down: usize,
+    /// phase ごとの係数。coefficients[phase][tap]
+    coefficients: Vec<Vec<f32>>,
+    /// channel ごとの直近 TAPS_PER_PHASE 個の入力。history[chan][0] が一番新しい
+    history: Vec<Vec<f32>>,
+    /// 次の出力が、up 倍した入力の何番目に当たるか。input_count * up を超えない間は出力できる
+    next_position: usize,
+    /// これまでに受け取った入力のフレーム数
+    input_count: usize,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, from_rate: u32, to_rate: u32) -> Result<Resampler> {
+        let (up, down) = ratio(from_rate, to_rate)?;
+
+        Ok(Resampler {
+            channels,
+            up,
+            down,
+            coefficients: design_filter(up, down),
+            history: vec![vec![0.0; TAPS_PER_PHASE]; channels],
+            next_position: 0,
+            input_count: 0,
+        })
+    }
+
+    /// 変換しない、つまり sample rate が同じかどうか
+    pub fn is_identity(&self) -> bool {
+        self.up == self.down
+    }
+
+    /// 出力のフレーム数を frames にするのに必要な、入力のおおよそのフレーム数
+    pub fn input_frames_for(&self, frames: usize) -> usize {
+        (frames * self.down).div_ceil(self.up)
+    }
+
+    /// 途切れたときなどに、それまでの入力を忘れる
+    pub fn reset(&mut self) {
+        for history in self.history.iter_mut() {
+            history.iter_mut().for_each(|v| *v = 0.0);
+        }
+        self.next_position = 0;
+        self.input_count = 0;
+    }
+
+    /// interleave されたフレームを変換して output の末尾に追加する
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.is_identity() {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        for frame in input.chunks_exact(self.channels) {
+            for (history, sample) in self.history.iter_mut().zip(frame) {
+                history.rotate_right(1);
+                history[0] = *sample;
+            }
+            self.input_count += 1;
+
+            // 今受け取った入力が、up 倍したときの最後の位置までの出力を作る
+            while self.next_position < self.input_count * self.up {
+                let phase = self.next_position % self.up;
+                let coefficients = &self.coefficients[phase];
+                for history in self.history.iter() {
+                    output.push(
+                        coefficients
+                            .iter()
+                            .zip(history.iter())
+                            .map(|(c, x)| c * x)
+                            .sum(),
+                    );
+                }
+                self.next_position += self.down;
+            }
+        }
+
+        // 位置が大きくなりすぎないように、消費した入力の分だけ戻しておく
+        let consumed = (self.next_position / self.up).min(self.input_count);
+        self.next_position -= consumed * self.up;
+        self.input_count -= consumed;
+    }
+}
+
+/// 一定の sample rate で作った音を別の sample rate に変換して、必要なフレーム数ずつ取り出す
+pub struct OutputResampler {
+    resampler: Resampler,
+    channels: usize,
+    /// 変換したがまだ取り出していないサンプル
+    pending: Vec<f32>,
+    generated: Vec<f32>,
+}
+
+impl OutputResampler {
+    pub fn new(channels: usize, from_rate: u32, to_rate: u32) -> Result<OutputResampler> {
+        Ok(OutputResampler {
+            resampler: Resampler::new(channels, from_rate, to_rate)?,
+            channels,
+            pending: Vec::new(),
+            generated: Vec::new(),
+        })
+    }
+
+    /// 変換後のフレームを frames 個 output に追加する
+    ///
+    /// 足りない分は generate に変換前のフレーム数を渡して、interleave されたサンプルを追加させる
+    pub fn fill<F>(&mut self, frames: usize, output: &mut Vec<f32>, mut generate: F)
+    where
+        F: FnMut(usize, &mut Vec<f32>),
+    {
+        let n_samples = frames * self.channels;
+        while self.pending.len() < n_samples {
+            let missing = (n_samples - self.pending.len()) / self.channels;
+            self.generated.clear();
+            generate(
+                self.resampler.input_frames_for(missing).max(1),
+                &mut self.generated,
+            );
+            self.resampler.process(&self.generated, &mut self.pending);
+        }
+        output.extend(self.pending.drain(..n_samples));
+    }
+}
+
+/// from_rate から to_rate に変換するときの (up, down)。扱えない組み合わせならエラー
+pub fn ratio(from_rate: u32, to_rate: u32) -> Result<(usize, usize)> {
+    if from_rate == 0 || to_rate == 0 {
+        return Err(Error::Config(format!(
+            "cannot resample from {} Hz to {} Hz",
+            from_rate, to_rate
+        )));
+    }
+    let divisor = gcd(from_rate as usize, to_rate as usize);
+    let up = to_rate as usize / divisor;
+    let down = from_rate as usize / divisor;
+    if up > MAX_PHASES || down > MAX_PHASES {
+        return Err(Error::Config(format!(
+            "unsupported sample rate ratio. {} Hz to {} Hz",
+            from_rate, to_rate
+        )));
+    }
+    Ok((up, down))
+}
+
+/// from_rate から to_rate に変換するときに filter で遅れる時間 [to_rate のフレーム数]
+///
+/// filter は係数の中心について対称なので、どの周波数も同じだけ遅れる。変換しないときは遅れない
+pub fn group_delay(from_rate: u32, to_rate: u32) -> Result<f64> {
+    let (up, down) = ratio(from_rate, to_rate)?;
+    if up == down {
+        return Ok(0.0);
+    }
+    // 係数の中心は up 倍した sample rate で (length - 1) / 2 番目にあり、出力は down 個おきに取り出す
+    Ok((TAPS_PER_PHASE * up - 1) as f64 / 2.0 / down as f64)
+}
+
+/// phase ごとに分けた、Blackman 窓付き sinc の低域通過フィルタ
+fn design_filter(up: usize, down: usize) -> Vec<Vec<f32>> {
+    let length = TAPS_PER_PHASE * up;
+    // up 倍した sample rate での遮断周波数。低い方の Nyquist 周波数の少し手前にする
+    let cutoff = 0.5 / up.max(down) as f64 * 0.95;
+    let center = (length - 1) as f64 / 2.0;
+
+    let prototype: Vec<f64> = (0..length)
+        .map(|i| {
+            let x = i as f64 - center;
+            let sinc = if x == 0.0 {
+                2.0 * cutoff
+            } else {
+                (2.0 * std::f64::consts::PI * cutoff * x).sin() / (std::f64::consts::PI * x)
+            };
+            let phase = 2.0 * std::f64::consts::PI * i as f64 / (length - 1) as f64;
+            let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+            // up 倍したときに 0 を挟んだ分だけ振幅が小さくなるので戻す
+            sinc * window * up as f64
+        })
+        .collect();
+
+    (0..up)
+        .map(|phase| {
+            (0..TAPS_PER_PHASE)
+                .map(|tap| prototype[phase + tap * up] as f32)
+                .collect()
+        })
+        .collect()
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// from_rate で freq Hz、振幅 0.5 の cos を frames フレーム分 2 channel で作る。2 channel 目は符号を反転する
+    fn tone(freq: f64, from_rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let phase = 2.0 * std::f64::consts::PI * freq * n as f64 / from_rate as f64;
+                let sample = (phase.cos() * 0.5) as f32;
+                [sample, -sample]
+            })
+            .collect()
+    }
+
+    fn resample(input: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+        let mut output = Vec::new();
+        Resampler::new(2, from_rate, to_rate)
+            .unwrap()
+            .process(input, &mut output);
+        output
+    }
+
+    const RATES: [(u32, u32); 4] = [
+        (44100, 48000),
+        (48000, 44100),
+        (96000, 48000),
+        (48000, 96000),
+    ];
+
+    #[test]
+    fn passes_dc() {
+        for (from_rate, to_rate) in RATES {
+            let output = resample(&vec![0.25; 2 * 4000], from_rate, to_rate);
+            // filter が埋まった後の出力
+            for sample in output[output.len() / 2..].iter() {
+                assert!(
+                    (sample.abs() - 0.25).abs() < 1e-3,
+                    "{} -> {}: {}",
+                    from_rate,
+                    to_rate,
+                    sample
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn passes_tone_with_group_delay() {
+        let freq = 1000.0;
+        for (from_rate, to_rate) in RATES {
+            let output = resample(
+                &tone(freq, from_rate, from_rate as usize / 10),
+                from_rate,
+                to_rate,
+            );
+            let delay = group_delay(from_rate, to_rate).unwrap();
+            // group_delay だけ遅れた、同じ振幅の cos になる
+            let start = output.len() / 4;
+            for (n, frame) in output.chunks_exact(2).enumerate().skip(start / 2) {
+                let phase = 2.0 * std::f64::consts::PI * freq * (n as f64 - delay) / to_rate as f64;
+                let expected = (phase.cos() * 0.5) as f32;
+                assert!(
+                    (frame[0] - expected).abs() < 2e-3,
+                    "{} -> {}: {} != {}",
+                    from_rate,
+                    to_rate,
+                    frame[0],
+                    expected
+                );
+                assert!(
+                    (frame[1] + expected).abs() < 2e-3,
+                    "{} -> {}: {} != {}",
+                    from_rate,
+                    to_rate,
+                    frame[1],
+                    -expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn chunked_input_matches_one_call() {
+        for (from_rate, to_rate) in RATES {
+            let input = tone(1000.0, from_rate, 3000);
+            let whole = resample(&input, from_rate, to_rate);
+
+            let mut resampler = Resampler::new(2, from_rate, to_rate).unwrap();
+            let mut chunked = Vec::new();
+            // フレームの数がばらばらになるように区切る
+            let mut rest = input.as_slice();
+            for frames in [1, 7, 48, 333].iter().cycle() {
+                if rest.is_empty() {
+                    break;
+                }
+                let (chunk, next) = rest.split_at((frames * 2).min(rest.len()));
+                resampler.process(chunk, &mut chunked);
+                rest = next;
+            }
+            assert_eq!(chunked, whole, "{} -> {}", from_rate, to_rate);
+        }
+    }
+
+    #[test]
+    fn identity_has_no_delay() {
+        assert_eq!(group_delay(48000, 48000).unwrap(), 0.0);
+        let input = tone(1000.0, 48000, 100);
+        assert_eq!(resample(&input, 48000, 48000), input);
+    }
+}
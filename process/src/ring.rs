@@ -0,0 +1,181 @@
+use rustfft::num_complex::Complex32;
+use std::sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering};
+
+/// channel ごとに固定長の領域を持つ、書き手が一つのリングバッファ
+///
+/// サンプルは先頭からの通し番号 (absolute index) で読む。書き手は読み手を待たずに古いサンプルを上書きし、
+/// 読み手は読んでいる間に上書きされたかどうかを後から確かめる
+pub struct RingBuffer {
+    /// f32 のビット列を channel ごとに持つ
+    channels: Vec<Box<[AtomicU32]>>,
+    capacity: usize,
+    /// 書き込みを始めたフレームの終わり。これより前の領域は上書きされているかもしれない
+    claimed: AtomicUsize,
+    /// 書き込みが終わったフレーム数
+    written: AtomicUsize,
+}
+
+impl RingBuffer {
+    /// channel ごとに capacity フレーム分の領域を確保する
+    pub fn new(n_chan: usize, capacity: usize) -> RingBuffer {
+        let capacity = capacity.max(1);
+        let channels = (0..n_chan)
+            .map(|_| (0..capacity).map(|_| AtomicU32::new(0)).collect())
+            .collect();
+        RingBuffer {
+            channels,
+            capacity,
+            claimed: AtomicUsize::new(0),
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get_n_chan(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// 書き込みが終わったフレーム数。これより前のサンプルが読める
+    pub fn written(&self) -> usize {
+        self.written.load(Ordering::Acquire)
+    }
+
+    /// まだ上書きされていない一番古いフレームの index
+    pub fn oldest(&self) -> usize {
+        self.written().saturating_sub(self.capacity)
+    }
+
+    /// interleave されたフレームを書き込む。書き手は一つのスレッドだけにすること
+    pub fn push(&self, samples: &[f32]) {
+        let n_chan = self.get_n_chan();
+        let frames = samples.len() / n_chan;
+        // 一度に capacity より多く書いても、残るのは最後の capacity フレームだけ
+        let skip = frames.saturating_sub(self.capacity);
+        let start = self.written.load(Ordering::Relaxed);
+        let end = start + frames;
+
+        // 読み手がこれから上書きする領域を読んでいないか確かめられるように、先に範囲を出しておく
+        self.claimed.store(end, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        for (offset, frame) in samples.chunks_exact(n_chan).enumerate().skip(skip) {
+            let slot = (start + offset) % self.capacity;
+            for (chan, sample) in frame.iter().enumerate() {
+                self.channels[chan][slot].store(sample.to_bits(), Ordering::Relaxed);
+            }
+        }
+
+        self.written.store(end, Ordering::Release);
+    }
+
+    /// chan の start から buffer の長さ分のサンプルを buffer の実部にコピーする
+    ///
+    /// まだ書かれていないか、読んでいる間に上書きされた場合は false を返す
+    pub fn set_buffer(&self, buffer: &mut [Complex32], chan: usize, start: usize) -> bool {
+        let end = start + buffer.len();
+        if end > self.written() || start < self.oldest() {
+            return false;
+        }
+
+        let samples = &self.channels[chan];
+        for (i, value) in buffer.iter_mut().enumerate() {
+            value.re = f32::from_bits(samples[(start + i) % self.capacity].load(Ordering::Relaxed));
+            value.im = 0.0;
+        }
+
+        // コピーしている間に書き手が start より後を上書きし始めていたら読み直しが必要
+        fence(Ordering::Acquire);
+        self.claimed.load(Ordering::Relaxed) <= start + self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// start から frames フレーム分の、channel ごとに符号を変えた通し番号
+    fn frames(start: usize, frames: usize) -> Vec<f32> {
+        (start..start + frames)
+            .flat_map(|n| [n as f32, -(n as f32)])
+            .collect()
+    }
+
+    fn read(ring: &RingBuffer, chan: usize, start: usize, len: usize) -> Option<Vec<f32>> {
+        let mut buffer = vec![Complex32::new(0.0, 0.0); len];
+        ring.set_buffer(&mut buffer, chan, start)
+            .then(|| buffer.iter().map(|value| value.re).collect())
+    }
+
+    #[test]
+    fn wraps_around() {
+        let ring = RingBuffer::new(2, 8);
+        ring.push(&frames(0, 6));
+        ring.push(&frames(6, 5));
+        assert_eq!(ring.written(), 11);
+
+        // 領域の終わりをまたいで読んでも通し番号の順になる
+        let expected: Vec<f32> = (5..11).map(|n| n as f32).collect();
+        assert_eq!(read(&ring, 0, 5, 6), Some(expected.clone()));
+        let negated: Vec<f32> = expected.iter().map(|n| -n).collect();
+        assert_eq!(read(&ring, 1, 5, 6), Some(negated));
+
+        // まだ書かれていないところは読めない
+        assert_eq!(read(&ring, 0, 8, 4), None);
+    }
+
+    #[test]
+    fn oldest_follows_capacity() {
+        let ring = RingBuffer::new(2, 8);
+        assert_eq!(ring.oldest(), 0);
+        ring.push(&frames(0, 5));
+        assert_eq!(ring.oldest(), 0);
+        ring.push(&frames(5, 7));
+        assert_eq!(ring.oldest(), 4);
+
+        // 一度に capacity より多く書いたときは最後の capacity フレームだけが残る
+        ring.push(&frames(12, 20));
+        assert_eq!(ring.written(), 32);
+        assert_eq!(ring.oldest(), 24);
+        let expected: Vec<f32> = (24..32).map(|n| n as f32).collect();
+        assert_eq!(read(&ring, 0, 24, 8), Some(expected));
+    }
+
+    #[test]
+    fn detects_overwritten_reads() {
+        let ring = RingBuffer::new(2, 8);
+        ring.push(&frames(0, 8));
+        assert!(read(&ring, 0, 0, 4).is_some());
+        ring.push(&frames(8, 1));
+        assert_eq!(read(&ring, 0, 0, 4), None);
+        assert!(read(&ring, 0, 1, 4).is_some());
+    }
+
+    #[test]
+    fn never_returns_torn_reads() {
+        // 書き手が読み手を待たずに上書きし続けても、true を返したときの中身は必ず通し番号どおり
+        let ring = Arc::new(RingBuffer::new(2, 64));
+        let writer = {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                for start in (0..200_000).step_by(16) {
+                    ring.push(&frames(start, 16));
+                }
+            })
+        };
+
+        let mut buffer = vec![Complex32::new(0.0, 0.0); 32];
+        while !writer.is_finished() {
+            // 一番古いところから読むと、読んでいる間に上書きされやすい
+            let start = ring.oldest();
+            if ring.set_buffer(&mut buffer, 0, start) {
+                for (i, value) in buffer.iter().enumerate() {
+                    assert_eq!(value.re, (start + i) as f32);
+                }
+            }
+        }
+        writer.join().unwrap();
+        let expected: Vec<f32> = (199_968..200_000).map(|n| n as f32).collect();
+        assert_eq!(read(&ring, 0, 199_968, 32), Some(expected));
+    }
+}
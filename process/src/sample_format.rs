@@ -0,0 +1,250 @@
+use hound::WavSpec;
+
+use super::error::{Error, Result};
+
+/// WAVEFORMATEX の wFormatTag の値
+pub const WAVE_FORMAT_PCM: u16 = 0x0001;
+pub const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+
+/// device や WAV ファイルのサンプルの形式。パイプラインの中はすべて f32 で扱い、入出力の境界でこれと変換する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    I16,
+    /// 3 byte に詰めた 24bit
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl SampleFormat {
+    /// wFormatTag と wBitsPerSample から形式を決める
+    ///
+    /// WAVE_FORMAT_EXTENSIBLE の場合は SubFormat の先頭 4 byte (WAVE_FORMAT_* と同じ値) を format_tag に渡すこと
+    pub fn from_wave_format(format_tag: u16, bits_per_sample: u16) -> Result<SampleFormat> {
+        match (format_tag, bits_per_sample) {
+            (WAVE_FORMAT_PCM, 16) => Ok(SampleFormat::I16),
+            (WAVE_FORMAT_PCM, 24) => Ok(SampleFormat::I24),
+            (WAVE_FORMAT_PCM, 32) => Ok(SampleFormat::I32),
+            (WAVE_FORMAT_IEEE_FLOAT, 32) => Ok(SampleFormat::F32),
+            (WAVE_FORMAT_IEEE_FLOAT, 64) => Ok(SampleFormat::F64),
+            _ => Err(Error::Format(format!(
+                "unsupported sample format. format tag: {:#06x}, bits per sample: {}",
+                format_tag, bits_per_sample
+            ))),
+        }
+    }
+
+    pub fn from_spec(spec: &WavSpec) -> Result<SampleFormat> {
+        let format_tag = match spec.sample_format {
+            hound::SampleFormat::Int => WAVE_FORMAT_PCM,
+            hound::SampleFormat::Float => WAVE_FORMAT_IEEE_FLOAT,
+        };
+        SampleFormat::from_wave_format(format_tag, spec.bits_per_sample)
+    }
+
+    pub fn to_spec(self, channels: u16, sample_rate: u32) -> WavSpec {
+        let sample_format = match self {
+            SampleFormat::I16 | SampleFormat::I24 | SampleFormat::I32 => hound::SampleFormat::Int,
+            SampleFormat::F32 | SampleFormat::F64 => hound::SampleFormat::Float,
+        };
+        WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: (self.bytes_per_sample() * 8) as u16,
+            sample_format,
+        }
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::I16 => 2,
+            SampleFormat::I24 => 3,
+            SampleFormat::I32 | SampleFormat::F32 => 4,
+            SampleFormat::F64 => 8,
+        }
+    }
+
+    /// little endian のバイト列を f32 にして output の末尾に追加する。端数のバイトは無視する
+    pub fn decode(self, bytes: &[u8], output: &mut Vec<f32>) {
+        let chunks = bytes.chunks_exact(self.bytes_per_sample());
+        match self {
+            SampleFormat::I16 => {
+                output.extend(chunks.map(|b| i16_to_f32(i16::from_le_bytes([b[0], b[1]]))))
+            }
+            SampleFormat::I24 => output.extend(chunks.map(|b| {
+                // 上位 3 byte に入れてから算術シフトで符号を拡張する
+                i24_to_f32(i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8)
+            })),
+            SampleFormat::I32 => output
+                .extend(chunks.map(|b| i32_to_f32(i32::from_le_bytes([b[0], b[1], b[2], b[3]])))),
+            SampleFormat::F32 => {
+                output.extend(chunks.map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+            }
+            SampleFormat::F64 => output.extend(chunks.map(|b| {
+                f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32
+            })),
+        }
+    }
+
+    /// samples を little endian で bytes に書き込む。samples が足りない分は無音にする
+    pub fn encode(self, samples: &[f32], bytes: &mut [u8]) {
+        let mut samples = samples.iter();
+        for b in bytes.chunks_exact_mut(self.bytes_per_sample()) {
+            let sample = samples.next().copied().unwrap_or(0.0);
+            match self {
+                SampleFormat::I16 => b.copy_from_slice(&f32_to_i16(sample).to_le_bytes()),
+                SampleFormat::I24 => b.copy_from_slice(&f32_to_i24(sample).to_le_bytes()[..3]),
+                SampleFormat::I32 => b.copy_from_slice(&f32_to_i32(sample).to_le_bytes()),
+                SampleFormat::F32 => b.copy_from_slice(&sample.to_le_bytes()),
+                SampleFormat::F64 => b.copy_from_slice(&(sample as f64).to_le_bytes()),
+            }
+        }
+    }
+}
+
+const I16_SCALE: f64 = (1u64 << 15) as f64;
+const I24_SCALE: f64 = (1u64 << 23) as f64;
+const I32_SCALE: f64 = (1u64 << 31) as f64;
+
+pub fn i16_to_f32(value: i16) -> f32 {
+    (value as f64 / I16_SCALE) as f32
+}
+
+/// value は符号を拡張した 24bit の値
+pub fn i24_to_f32(value: i32) -> f32 {
+    (value as f64 / I24_SCALE) as f32
+}
+
+pub fn i32_to_f32(value: i32) -> f32 {
+    (value as f64 / I32_SCALE) as f32
+}
+
+/// [-1, 1) を超える値は飽和させる
+pub fn f32_to_i16(value: f32) -> i16 {
+    to_int(value, I16_SCALE) as i16
+}
+
+/// 符号を拡張した 24bit の値を返す
+pub fn f32_to_i24(value: f32) -> i32 {
+    to_int(value, I24_SCALE) as i32
+}
+
+pub fn f32_to_i32(value: f32) -> i32 {
+    to_int(value, I32_SCALE) as i32
+}
+
+fn to_int(value: f32, scale: f64) -> i64 {
+    // NaN は無音にする
+    if value.is_nan() {
+        return 0;
+    }
+    (value as f64 * scale).round().clamp(-scale, scale - 1.0) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [SampleFormat; 5] = [
+        SampleFormat::I16,
+        SampleFormat::I24,
+        SampleFormat::I32,
+        SampleFormat::F32,
+        SampleFormat::F64,
+    ];
+
+    #[test]
+    fn integer_full_scale() {
+        assert_eq!(i16_to_f32(i16::MIN), -1.0);
+        assert_eq!(i24_to_f32(-(1 << 23)), -1.0);
+        assert_eq!(i32_to_f32(i32::MIN), -1.0);
+        assert_eq!(i16_to_f32(1 << 14), 0.5);
+
+        assert_eq!(f32_to_i16(-1.0), i16::MIN);
+        assert_eq!(f32_to_i24(-1.0), -(1 << 23));
+        assert_eq!(f32_to_i32(-1.0), i32::MIN);
+        assert_eq!(f32_to_i16(0.5), 1 << 14);
+    }
+
+    #[test]
+    fn integer_saturates() {
+        assert_eq!(f32_to_i16(1.0), i16::MAX);
+        assert_eq!(f32_to_i16(2.0), i16::MAX);
+        assert_eq!(f32_to_i16(-2.0), i16::MIN);
+        assert_eq!(f32_to_i24(1.5), (1 << 23) - 1);
+        assert_eq!(f32_to_i32(1.0), i32::MAX);
+        assert_eq!(f32_to_i32(f32::NAN), 0);
+    }
+
+    #[test]
+    fn i24_is_packed_and_sign_extended() {
+        let mut bytes = [0u8; 6];
+        SampleFormat::I24.encode(&[-0.5, 0.25], &mut bytes);
+        assert_eq!(bytes, [0x00, 0x00, 0xC0, 0x00, 0x00, 0x20]);
+
+        let mut decoded = Vec::new();
+        SampleFormat::I24.decode(&bytes, &mut decoded);
+        assert_eq!(decoded, vec![-0.5, 0.25]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples = [0.0, 0.5, -0.5, 0.123_456, -0.999, 0.75];
+        for format in FORMATS {
+            let mut bytes = vec![0u8; samples.len() * format.bytes_per_sample()];
+            format.encode(&samples, &mut bytes);
+            let mut decoded = Vec::new();
+            format.decode(&bytes, &mut decoded);
+
+            // 量子化の誤差は 1 LSB の半分まで
+            let tolerance = match format {
+                SampleFormat::I16 => 0.5 / I16_SCALE as f32,
+                SampleFormat::I24 => 0.5 / I24_SCALE as f32,
+                _ => f32::EPSILON,
+            };
+            assert_eq!(decoded.len(), samples.len());
+            for (d, s) in decoded.iter().zip(samples.iter()) {
+                assert!((d - s).abs() <= tolerance, "{:?}: {} != {}", format, d, s);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_pads_with_silence() {
+        for format in FORMATS {
+            let mut bytes = vec![0xFFu8; 3 * format.bytes_per_sample()];
+            format.encode(&[0.5], &mut bytes);
+            let mut decoded = Vec::new();
+            format.decode(&bytes, &mut decoded);
+            assert_eq!(decoded[1..], [0.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn decode_ignores_trailing_bytes() {
+        let mut decoded = Vec::new();
+        SampleFormat::I16.decode(&[0x00, 0x40, 0x00], &mut decoded);
+        assert_eq!(decoded, vec![0.5]);
+    }
+
+    #[test]
+    fn wave_format() {
+        assert_eq!(
+            SampleFormat::from_wave_format(WAVE_FORMAT_PCM, 24).unwrap(),
+            SampleFormat::I24
+        );
+        assert_eq!(
+            SampleFormat::from_wave_format(WAVE_FORMAT_IEEE_FLOAT, 64).unwrap(),
+            SampleFormat::F64
+        );
+        assert!(SampleFormat::from_wave_format(WAVE_FORMAT_PCM, 8).is_err());
+        assert!(SampleFormat::from_wave_format(WAVE_FORMAT_IEEE_FLOAT, 16).is_err());
+        assert!(SampleFormat::from_wave_format(0xFFFE, 32).is_err());
+
+        for format in FORMATS {
+            let spec = format.to_spec(2, 48000);
+            assert_eq!(SampleFormat::from_spec(&spec).unwrap(), format);
+        }
+    }
+}
@@ -0,0 +1,566 @@
+use hound::WavSpec;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use super::config::{PipelineConfig, PipelineOptions};
+use super::error::{Error, Result};
+use super::offline::BlockProcessor;
+use super::packet::{frames_to_timestamp, Packet, PacketFlags};
+use super::sample_format::SampleFormat;
+use super::sink::AudioSink;
+use super::source::AudioSource;
+use super::wav::WavFileSource;
+
+/// スピーカーから出した音に加えて、マイクやloopbackに入ってくる外乱
+#[derive(Debug, Clone)]
+pub enum Disturbance {
+    /// 全チャンネルに同じ、freqs の正弦波を足したもの。amplitude はそれぞれの正弦波の振幅
+    Tones { freqs: Vec<f64>, amplitude: f64 },
+    /// 一様分布のホワイトノイズ。seed が同じなら毎回同じ系列になる
+    Noise { amplitude: f32, seed: u64 },
+    /// interleave されたサンプル。最後まで行ったら最初に戻る
+    Wav(Vec<f32>),
+}
+
+impl Disturbance {
+    /// WAV ファイルを外乱として読み込む
+    ///
+    /// channels と sample_rate は SimConfig のもの。変換はしないので、WAV がそれと違えば Error::Format を返す
+    pub fn load_wav<P: AsRef<Path>>(
+        path: P,
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Disturbance> {
+        let mut source = WavFileSource::open(path.as_ref())?;
+        let spec = source.start()?;
+        if spec.channels != channels || spec.sample_rate != sample_rate {
+            return Err(Error::Format(format!(
+                "{}: disturbance WAV does not match the simulation. channels: {} (expected {}), sample rate: {} (expected {})",
+                path.as_ref().display(),
+                spec.channels,
+                channels,
+                spec.sample_rate,
+                sample_rate
+            )));
+        }
+        let mut samples = Vec::new();
+        while let Some(packet) = source.read()? {
+            samples.extend_from_slice(&packet.samples);
+        }
+        source.stop()?;
+        Ok(Disturbance::Wav(samples))
+    }
+}
+
+/// シミュレーションの設定
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub disturbance: Disturbance,
+    /// sink に書いた音が source に戻ってくるまでのフレーム数
+    pub delay: usize,
+    /// sink に書いた音が source に戻ってくるときに掛かる倍率
+    pub gain: f32,
+    /// source が一度に返すフレーム数。既定では 1ms 分
+    pub block_frames: usize,
+    /// 仮想的な device の buffer の形式。source と sink の音はこの形式を通して量子化される
+    pub sample_format: SampleFormat,
+    /// source が返す何番目の packet にどのフラグを付けるか。フラグを付けるだけで、サンプルは変えない
+    pub flags: Vec<(usize, PacketFlags)>,
+}
+
+impl SimConfig {
+    pub fn new(disturbance: Disturbance) -> SimConfig {
+        SimConfig {
+            channels: 2,
+            sample_rate: 48000,
+            disturbance,
+            delay: 0,
+            gain: 1.0,
+            block_frames: 48,
+            sample_format: SampleFormat::F32,
+            flags: Vec::new(),
+        }
+    }
+
+    fn spec(&self) -> WavSpec {
+        self.sample_format.to_spec(self.channels, self.sample_rate)
+    }
+
+    /// samples を device の buffer の形式に変換して戻す
+    fn quantize(&self, samples: &[f32]) -> Vec<f32> {
+        let mut bytes = vec![0; samples.len() * self.sample_format.bytes_per_sample()];
+        self.sample_format.encode(samples, &mut bytes);
+        let mut quantized = Vec::with_capacity(samples.len());
+        self.sample_format.decode(&bytes, &mut quantized);
+        quantized
+    }
+}
+
+struct SimState {
+    /// source が返したフレーム数。これが仮想的なサンプルクロックになる
+    clock: usize,
+    /// sink が書いたフレーム数
+    written: usize,
+    /// source に戻るのを待っている音。先頭が次に source が返すフレーム
+    feedback: VecDeque<f32>,
+    /// 外乱の生成に使う乱数の状態
+    noise_state: u64,
+}
+
+struct Shared {
+    config: SimConfig,
+    state: Mutex<SimState>,
+    cond: Condvar,
+}
+
+/// 待っている相手がいなくなったときに抜けられるように、待つのはこの時間まで
+const WAIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// シミュレーションの音を受け取る sink と、それを遅延、倍率、外乱を加えて返す source の組を作る
+pub fn simulated_pair(config: SimConfig) -> (SimulatedSource, SimulatedSink) {
+    let n_chan = config.channels as usize;
+    let mut feedback = VecDeque::new();
+    feedback.resize(config.delay * n_chan, 0.0);
+    let noise_state = match config.disturbance {
+        Disturbance::Noise { seed, .. } => seed.max(1),
+        _ => 1,
+    };
+
+    let shared = Arc::new(Shared {
+        config,
+        state: Mutex::new(SimState {
+            clock: 0,
+            written: 0,
+            feedback,
+            noise_state,
+        }),
+        cond: Condvar::new(),
+    });
+
+    (
+        SimulatedSource {
+            shared: shared.clone(),
+        },
+        SimulatedSink { shared },
+    )
+}
+
+/// sink の音に外乱を加えて返す source
+pub struct SimulatedSource {
+    shared: Arc<Shared>,
+}
+
+impl AudioSource for SimulatedSource {
+    fn start(&mut self) -> Result<WavSpec> {
+        Ok(self.shared.config.spec())
+    }
+
+    fn read(&mut self) -> Result<Option<Packet>> {
+        let config = &self.shared.config;
+        let n_chan = config.channels as usize;
+        let block_samples = config.block_frames * n_chan;
+
+        // sink が一ブロック分の音を書くまで待つ
+        let state = self.shared.state.lock().unwrap();
+        let (mut state, _) = self
+            .shared
+            .cond
+            .wait_timeout_while(state, WAIT_TIMEOUT, |state| {
+                state.feedback.len() < block_samples
+            })
+            .unwrap();
+        if state.feedback.len() < block_samples {
+            return Ok(Some(Packet::empty()));
+        }
+
+        let mut buffer = Vec::with_capacity(block_samples);
+
+        for frame in 0..config.block_frames {
+            let t = state.clock + frame;
+            for chan in 0..n_chan {
+                let feedback = state.feedback.pop_front().unwrap_or(0.0);
+                let disturbance = match &config.disturbance {
+                    Disturbance::Tones { freqs, amplitude } => freqs
+                        .iter()
+                        .map(|freq| {
+                            ((freq * t as f64 / config.sample_rate as f64
+                                * std::f64::consts::PI
+                                * 2.)
+                                .cos()
+                                * amplitude) as f32
+                        })
+                        .sum(),
+                    Disturbance::Noise { amplitude, .. } => {
+                        let noise = next_noise(&mut state.noise_state);
+                        noise * amplitude
+                    }
+                    Disturbance::Wav(samples) => {
+                        if samples.is_empty() {
+                            0.0
+                        } else {
+                            samples[(t * n_chan + chan) % samples.len()]
+                        }
+                    }
+                };
+                buffer.push(disturbance + feedback * config.gain);
+            }
+        }
+        let timestamp = frames_to_timestamp(state.clock as u64, config.sample_rate);
+        let block = state.clock / config.block_frames;
+        let flags = config
+            .flags
+            .iter()
+            .filter(|(index, _)| *index == block)
+            .fold(PacketFlags::default(), |flags, (_, flag)| {
+                PacketFlags(flags.0 | flag.0)
+            });
+        state.clock += config.block_frames;
+        self.shared.cond.notify_all();
+
+        Ok(Some(Packet {
+            samples: config.quantize(&buffer),
+            frames: config.block_frames,
+            flags,
+            timestamp,
+        }))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// 書き込まれた音を SimulatedSource に戻す sink
+pub struct SimulatedSink {
+    shared: Arc<Shared>,
+}
+
+impl AudioSink for SimulatedSink {
+    fn start(&mut self) -> Result<WavSpec> {
+        Ok(self.shared.config.spec())
+    }
+
+    fn wait_writable(&mut self) -> Result<usize> {
+        let block_frames = self.shared.config.block_frames;
+
+        // source より一ブロック以上先には進まないようにする
+        let state = self.shared.state.lock().unwrap();
+        let (state, _) = self
+            .shared
+            .cond
+            .wait_timeout_while(state, WAIT_TIMEOUT, |state| {
+                state.written >= state.clock + block_frames
+            })
+            .unwrap();
+        Ok((state.clock + block_frames).saturating_sub(state.written))
+    }
+
+    fn write(&mut self, samples: &[f32], is_silent: bool) -> Result<()> {
+        let n_chan = self.shared.config.channels as usize;
+        if !samples.len().is_multiple_of(n_chan) {
+            return Err(Error::Format(format!(
+                "simulated sink got {} samples for {} channels",
+                samples.len(),
+                n_chan
+            )));
+        }
+
+        let samples = self.shared.config.quantize(samples);
+        let mut state = self.shared.state.lock().unwrap();
+        for sample in samples.iter() {
+            state
+                .feedback
+                .push_back(if is_silent { 0.0 } else { *sample });
+        }
+        state.written += samples.len() / n_chan;
+        self.shared.cond.notify_all();
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// xorshift64* で [-1, 1) の一様乱数を作る
+fn next_noise(state: &mut u64) -> f32 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    let value = state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+    ((value >> 40) as f32 / (1u64 << 23) as f32) - 1.0
+}
+
+/// シミュレーションの結果
+#[derive(Debug, Clone)]
+pub struct SimReport {
+    pub frames: usize,
+    pub update_count: usize,
+    /// source から返ってきた音の、ブロックごとの RMS
+    pub residual_rms: Vec<f32>,
+}
+
+/// 仮想的なサンプルクロックで frames フレーム分の閉ループを回す
+///
+/// RenderQueue の音を sink に書き、source から返ってきた音を解析して制御する、を一ブロックずつ繰り返す
+pub fn run(config: SimConfig, options: &PipelineOptions, frames: usize) -> Result<SimReport> {
+    let (mut source, mut sink) = simulated_pair(config);
+
+    let spec = source.start()?;
+    sink.start()?;
+
+    let mut processor = BlockProcessor::new(PipelineConfig::new(&spec, options)?)?;
+    let mut anti = Vec::new();
+    let mut residual_rms = Vec::new();
+    let mut total_frames = 0;
+    while total_frames < frames {
+        anti.clear();
+        let writable = sink.wait_writable()?;
+        processor.generate(writable, &mut anti);
+        sink.write(&anti, false)?;
+
+        let captured = match source.read()? {
+            Some(packet) => packet,
+            None => break,
+        };
+        if captured.frames == 0 {
+            return Err(Error::Device(format!(
+                "simulated source stalled after {} frames",
+                total_frames
+            )));
+        }
+
+        processor.analyze(&captured.samples);
+
+        let power =
+            captured.samples.iter().map(|v| v * v).sum::<f32>() / captured.samples.len() as f32;
+        residual_rms.push(power.sqrt());
+        total_frames += captured.frames;
+    }
+
+    source.stop()?;
+    sink.stop()?;
+
+    Ok(SimReport {
+        frames: total_frames,
+        update_count: processor.update_count(),
+        residual_rms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+    use crate::wav::WavFileSink;
+    use crate::window::WindowFunction;
+
+    /// 最後の 100 ブロックの RMS の平均
+    fn settled_rms(report: &SimReport) -> f32 {
+        let last = &report.residual_rms[report.residual_rms.len() - 100..];
+        last.iter().sum::<f32>() / last.len() as f32
+    }
+
+    fn tone(freq: f64) -> SimConfig {
+        SimConfig::new(Disturbance::Tones {
+            freqs: vec![freq],
+            amplitude: 0.5,
+        })
+    }
+
+    #[test]
+    fn cancels_off_bin_targets() {
+        // 窓の先頭が周期の途中から始まっても、同じ時刻を基準にした位相で制御する
+        for (freq, window_milli_second) in [(1000.0, 20.0), (1020.0, 20.0), (1010.0, 10.0)] {
+            let options = PipelineOptions {
+                target_freqs: vec![freq as f32],
+                window_milli_second,
+                ..Default::default()
+            };
+            let report = run(tone(freq), &options, 48000).unwrap();
+            let rms = settled_rms(&report);
+            assert!(
+                rms < 1e-3,
+                "{} Hz, {} ms: {}",
+                freq,
+                window_milli_second,
+                rms
+            );
+        }
+    }
+
+    #[test]
+    fn cancels_with_any_window() {
+        for window in [
+            WindowFunction::Hann,
+            WindowFunction::BlackmanHarris,
+            WindowFunction::Kaiser,
+        ] {
+            let options = PipelineOptions {
+                target_freqs: vec![1020.0],
+                window_milli_second: 20.0,
+                window,
+                ..Default::default()
+            };
+            let rms = settled_rms(&run(tone(1020.0), &options, 48000).unwrap());
+            assert!(rms < 1e-3, "{:?}: {}", window, rms);
+        }
+    }
+
+    #[test]
+    fn cancels_through_resampler() {
+        // device と解析の sample rate が違っても、変換の filter の遅れを差し引いて制御する
+        for sample_rate in [44100, 96000] {
+            let options = PipelineOptions {
+                target_freqs: vec![1000.0],
+                window_milli_second: 20.0,
+                ..Default::default()
+            };
+            let mut config = tone(1000.0);
+            config.sample_rate = sample_rate;
+            config.block_frames = sample_rate as usize / 1000;
+            let report = run(config, &options, sample_rate as usize).unwrap();
+            let rms = settled_rms(&report);
+            assert!(rms < 1e-3, "{} Hz device: {}", sample_rate, rms);
+        }
+    }
+
+    #[test]
+    fn cancels_without_delay() {
+        let options = PipelineOptions::default();
+        let report = run(tone(1000.0), &options, 48000).unwrap();
+        assert_eq!(report.frames, 48000);
+        assert!(report.update_count > 0);
+        // 打ち消す前の 0.5 の正弦波の RMS は 0.354
+        assert!(report.residual_rms[0] > 0.3);
+        assert!(settled_rms(&report) < 1e-3, "{}", settled_rms(&report));
+    }
+
+    #[test]
+    fn cancels_with_configured_latency() {
+        for delay in [10, 48, 130] {
+            let mut config = tone(1000.0);
+            config.delay = delay;
+            let options = PipelineOptions {
+                latency_milli_second: delay as f32 * 1000.0 / 48000.0,
+                ..Default::default()
+            };
+            let rms = settled_rms(&run(config, &options, 48000).unwrap());
+            assert!(rms < 1e-3, "delay {}: {}", delay, rms);
+        }
+    }
+
+    #[test]
+    fn decides_every_hop() {
+        // 窓の途中で音が変わっても判断できるので、窓が長くても hop ごとに更新する
+        let options = PipelineOptions {
+            target_freqs: vec![1020.0],
+            window_milli_second: 20.0,
+            hop_milli_second: 0.5,
+            window: WindowFunction::Hann,
+            estimator: crate::Estimator::SlidingDft,
+            ..Default::default()
+        };
+        let report = run(tone(1020.0), &options, 48000).unwrap();
+        let windows = (48000 - 960) / 24 + 1;
+        assert_eq!(report.update_count, windows * 2);
+        assert!(settled_rms(&report) < 1e-3, "{}", settled_rms(&report));
+        // 最初の窓が揃ったらすぐに打ち消す
+        let settled = report.residual_rms.iter().position(|rms| *rms < 1e-3);
+        assert!(settled.is_some_and(|block| block <= 21), "{:?}", settled);
+    }
+
+    #[test]
+    fn cancels_when_hop_does_not_divide_window() {
+        // 44.1kHz で解析すると hop は 44 サンプル、窓は 441 サンプルになり、窓の先頭は周期の途中から始まる
+        let options = PipelineOptions {
+            target_freqs: vec![1000.0],
+            window_milli_second: 10.0,
+            analysis_sample_rate: 44100,
+            ..Default::default()
+        };
+        let mut config = tone(1000.0);
+        config.sample_rate = 44100;
+        config.block_frames = 44;
+        let rms = settled_rms(&run(config, &options, 44100).unwrap());
+        assert!(rms < 1e-3, "{}", rms);
+    }
+
+    #[test]
+    fn cancels_several_targets() {
+        // target ごとの generator を足して出しても、それぞれの bin で打ち消す。3020 Hz は bin の中心にない
+        let options = PipelineOptions {
+            target_freqs: vec![1000.0, 3020.0],
+            window_milli_second: 20.0,
+            window: WindowFunction::BlackmanHarris,
+            ..Default::default()
+        };
+        let config = SimConfig::new(Disturbance::Tones {
+            freqs: vec![1000.0, 3020.0],
+            amplitude: 0.25,
+        });
+        let rms = settled_rms(&run(config, &options, 48000).unwrap());
+        assert!(rms < 1e-3, "{}", rms);
+    }
+
+    #[test]
+    fn stays_stable_under_noise() {
+        // 打ち消す音がない外乱に対しては、音を足して大きくしない
+        let options = PipelineOptions {
+            target_freqs: vec![1020.0],
+            window_milli_second: 20.0,
+            ..Default::default()
+        };
+        let config = SimConfig::new(Disturbance::Noise {
+            amplitude: 0.1,
+            seed: 7,
+        });
+        let report = run(config.clone(), &options, 48000).unwrap();
+        let again = run(config, &options, 48000).unwrap();
+        assert_eq!(report.residual_rms, again.residual_rms);
+
+        // 制御を始める前の最初の 10 ブロックはノイズだけ
+        let noise = report.residual_rms[..10].iter().sum::<f32>() / 10.0;
+        let rms = settled_rms(&report);
+        assert!(rms < noise * 1.1, "{} > {}", rms, noise);
+    }
+
+    #[test]
+    fn cancels_wav_disturbance() {
+        let path = temp_path("sim-disturbance.wav");
+        // 1020 Hz は 800 フレームでちょうど 17 周期なので、繰り返しても途切れない
+        let mut sink = WavFileSink::create(&path, 2, 48000).unwrap();
+        let samples: Vec<f32> = (0..4800)
+            .flat_map(|n| {
+                let phase = 2.0 * std::f64::consts::PI * 1020.0 * n as f64 / 48000.0;
+                [(0.5 * phase.cos()) as f32, (0.3 * phase.sin()) as f32]
+            })
+            .collect();
+        sink.write(&samples, false).unwrap();
+        sink.stop().unwrap();
+
+        let disturbance = Disturbance::load_wav(&path, 2, 48000).unwrap();
+        let mismatched = [
+            Disturbance::load_wav(&path, 1, 48000),
+            Disturbance::load_wav(&path, 2, 44100),
+        ];
+        std::fs::remove_file(&path).unwrap();
+        for result in mismatched {
+            assert!(matches!(result, Err(Error::Format(_))), "{:?}", result);
+        }
+
+        let options = PipelineOptions {
+            target_freqs: vec![1020.0],
+            window_milli_second: 20.0,
+            ..Default::default()
+        };
+        let report = run(SimConfig::new(disturbance), &options, 48000).unwrap();
+        let rms = settled_rms(&report);
+        assert!(rms < 1e-3, "{}", rms);
+    }
+}
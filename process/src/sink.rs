@@ -0,0 +1,24 @@
+use hound::WavSpec;
+
+use super::error::Result;
+
+/// render スレッドが生成した音を受け取るもの
+///
+/// WASAPI の render device もこれの実装の一つで、render スレッドはこの trait を通してのみ音を出す
+pub trait AudioSink {
+    /// 出力を開始して、実際に使う wave format を返す
+    fn start(&mut self) -> Result<WavSpec>;
+
+    /// 次に書き込めるフレーム数を返す
+    ///
+    /// 書き込めるようになるまで待つことがある
+    fn wait_writable(&mut self) -> Result<usize>;
+
+    /// interleave されたフレームを書き込む
+    ///
+    /// is_silent が true のときは samples の中身を無音として扱ってよい
+    fn write(&mut self, samples: &[f32], is_silent: bool) -> Result<()>;
+
+    /// 出力を終了する
+    fn stop(&mut self) -> Result<()>;
+}
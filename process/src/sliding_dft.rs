@@ -0,0 +1,330 @@
+use rustfft::num_complex::{Complex32, Complex64};
+use std::f64::consts::PI;
+use std::sync::mpsc::{Receiver, Sender};
+
+use super::config::PipelineConfig;
+use super::error::{Error, Result};
+use super::fft::FftEvent;
+use super::packet::Packet;
+use super::record::RecordEvent;
+use super::resample::Resampler;
+use super::window::{cosine_sum_terms, Window};
+
+/// target_freqs の bin を 1 サンプルごとに更新する sliding DFT
+///
+/// 窓関数は周波数領域で隣の bin と足し合わせて掛けるので、cos の和で表せる窓だけを扱える。
+/// 結果は同じ窓を FFT した bin と同じになる
+pub struct SlidingDft {
+    window_size: usize,
+    hop_size: usize,
+    /// 窓の cos の項の係数
+    terms: &'static [f64],
+    /// 計算する bin。target ごとに、target の bin とその前後 terms.len() - 1 個ずつが並ぶ
+    bins: Vec<usize>,
+    /// bin ごとの e^{j2πm/N}
+    twiddles: Vec<Complex64>,
+    channels: Vec<ChannelState>,
+    /// 次に書き込む history の位置。一番古いサンプルの位置でもある
+    position: usize,
+    /// これまでに入れたフレーム数
+    written: usize,
+    /// 次に結果を出す窓の先頭の index
+    next_index: usize,
+    /// 最後に直接計算し直してから入れたフレーム数
+    since_refresh: usize,
+}
+
+struct ChannelState {
+    /// 直近の window_size サンプル
+    history: Vec<f64>,
+    /// bins のそれぞれの、窓を掛ける前の DFT の値
+    values: Vec<Complex64>,
+}
+
+impl SlidingDft {
+    pub fn new(config: &PipelineConfig) -> Result<SlidingDft> {
+        let terms = cosine_sum_terms(config.window).ok_or_else(|| {
+            Error::Config(format!(
+                "the sliding-dft estimator does not support the {:?} window",
+                config.window
+            ))
+        })?;
+        let size = config.window_size;
+        let spread = terms.len() as isize - 1;
+        let bins: Vec<usize> = (0..config.target_freqs.len())
+            .flat_map(|target| {
+                let center = config.target_freq_index(target) as isize;
+                (-spread..=spread).map(move |k| (center + k).rem_euclid(size as isize) as usize)
+            })
+            .collect();
+        let twiddles = bins
+            .iter()
+            .map(|bin| Complex64::from_polar(1.0, 2.0 * PI * *bin as f64 / size as f64))
+            .collect();
+
+        Ok(SlidingDft {
+            window_size: size,
+            hop_size: config.hop_size,
+            terms,
+            channels: (0..config.channels)
+                .map(|_| ChannelState {
+                    history: vec![0.0; size],
+                    values: vec![Complex64::new(0.0, 0.0); bins.len()],
+                })
+                .collect(),
+            bins,
+            twiddles,
+            position: 0,
+            written: 0,
+            next_index: 0,
+            since_refresh: 0,
+        })
+    }
+
+    /// これまでに入れたフレーム数
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// channel ごとのサンプルを 1 フレーム入れる。hop ごとの窓が揃ったら、その窓の先頭の index を返す
+    pub fn push_frame(&mut self, frame: &[f32]) -> Option<usize> {
+        for (state, sample) in self.channels.iter_mut().zip(frame) {
+            let sample = *sample as f64;
+            let oldest = state.history[self.position];
+            state.history[self.position] = sample;
+            for (value, twiddle) in state.values.iter_mut().zip(self.twiddles.iter()) {
+                *value = (*value + sample - oldest) * twiddle;
+            }
+        }
+        self.position = (self.position + 1) % self.window_size;
+        self.written += 1;
+
+        // 漸化式の丸め誤差は消えずに溜まっていくので、窓一つ分ごとに直接計算し直す
+        self.since_refresh += 1;
+        if self.since_refresh >= self.window_size {
+            self.refresh();
+        }
+
+        if self.written < self.next_index + self.window_size {
+            return None;
+        }
+        let index = self.next_index;
+        self.next_index += self.hop_size;
+        Some(index)
+    }
+
+    /// 最後に揃った窓の、窓を掛けた target ごとの bin を values に入れる
+    pub fn estimate(&self, chan: usize, values: &mut Vec<Complex32>) {
+        values.clear();
+        let per_target = 2 * self.terms.len() - 1;
+        let center = self.terms.len() - 1;
+        for bins in self.channels[chan].values.chunks_exact(per_target) {
+            let mut value = bins[center] * self.terms[0];
+            for (k, a) in self.terms.iter().enumerate().skip(1) {
+                let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                value += (bins[center - k] + bins[center + k]) * (sign * a / 2.0);
+            }
+            values.push(Complex32::new(value.re as f32, value.im as f32));
+        }
+    }
+
+    /// データが途切れたときに、それまでのサンプルを捨てる。次に入れるサンプルの index を返す
+    pub fn reset(&mut self) -> usize {
+        for state in self.channels.iter_mut() {
+            state.history.iter_mut().for_each(|sample| *sample = 0.0);
+            state
+                .values
+                .iter_mut()
+                .for_each(|value| *value = Complex64::new(0.0, 0.0));
+        }
+        self.since_refresh = 0;
+        self.next_index = self.next_index.max(self.written);
+        self.written
+    }
+
+    fn refresh(&mut self) {
+        let size = self.window_size;
+        let position = self.position;
+        for state in self.channels.iter_mut() {
+            let history = &state.history;
+            for (value, bin) in state.values.iter_mut().zip(self.bins.iter()) {
+                *value = (0..size)
+                    .map(|n| {
+                        let sample = history[(position + n) % size];
+                        let phase = -2.0 * PI * ((bin * n) % size) as f64 / size as f64;
+                        Complex64::from_polar(sample, phase)
+                    })
+                    .sum();
+            }
+        }
+        self.since_refresh = 0;
+    }
+}
+
+/// receiver から来る音を sliding DFT に通し、hop ごとに fft_process_thread_func と同じ FftEvent を sender に送る
+///
+/// 窓ごとに計算し直さないので、リングバッファと worker は使わずにこのスレッドだけで行う。
+/// tx_record があれば、解析の sample rate に変換した音をそこにも送る
+pub fn sliding_dft_thread_func(
+    config: PipelineConfig,
+    receiver: Receiver<Packet>,
+    sender: Sender<FftEvent>,
+    tx_record: Option<Sender<RecordEvent>>,
+) -> Result<()> {
+    let mut sliding = SlidingDft::new(&config)?;
+    let window = Window::new(&config);
+    let mut resampler = Resampler::new(
+        config.channels,
+        config.device_sample_rate as u32,
+        config.sample_rate as u32,
+    )?;
+    let mut resampled = Vec::new();
+    let mut values = Vec::new();
+
+    for packet in receiver {
+        // 最初の packet は前がないので途切れとは扱わない
+        if packet.flags.is_discontinuity() && sliding.written() != 0 {
+            let index = sliding.reset();
+            resampler.reset();
+            sender
+                .send(FftEvent::Discontinuity { index })
+                .map_err(|_| Error::ChannelDisconnected("fft result"))?;
+        }
+        resampled.clear();
+        resampler.process(&packet.samples, &mut resampled);
+        if let Some(tx_record) = &tx_record {
+            // 記録が止まっても打ち消しは続ける
+            let _ = tx_record.send(RecordEvent::Captured {
+                index: sliding.written(),
+                samples: resampled.clone(),
+            });
+        }
+
+        for frame in resampled.chunks_exact(config.channels) {
+            let index = match sliding.push_frame(frame) {
+                Some(index) => index,
+                None => continue,
+            };
+            for chan in 0..config.channels {
+                sliding.estimate(chan, &mut values);
+                for (target, value) in values.iter().enumerate() {
+                    sender
+                        .send(FftEvent::Bin {
+                            chan,
+                            target,
+                            index,
+                            value: window.correct(target, index, *value),
+                        })
+                        .map_err(|_| Error::ChannelDisconnected("fft result"))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::estimator::{BinEstimator, Estimator};
+    use crate::test_utils::input;
+    use crate::window::WindowFunction;
+
+    fn config(window: WindowFunction, estimator: Estimator) -> PipelineConfig {
+        crate::test_utils::config(
+            2,
+            crate::PipelineOptions {
+                window,
+                estimator,
+                // 0 Hz の隣の bin も使うように、低い周波数も入れる
+                target_freqs: vec![200.0, 1000.0, 3020.0],
+                window_milli_second: 5.0,
+                hop_milli_second: 0.5,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// index から始まる窓を FFT して求めた bin
+    fn expected(
+        config: &PipelineConfig,
+        samples: &[f32],
+        chan: usize,
+        index: usize,
+    ) -> Vec<Complex32> {
+        let window = Window::new(config);
+        let mut buffer: Vec<Complex32> = (index..index + config.window_size)
+            .map(|n| Complex32::new(samples[n * config.channels + chan], 0.0))
+            .collect();
+        window.apply(&mut buffer);
+        let mut values = Vec::new();
+        BinEstimator::new(config).estimate(&mut buffer, &mut values);
+        values
+    }
+
+    #[test]
+    fn matches_fft() {
+        for function in [
+            WindowFunction::Rectangular,
+            WindowFunction::Hann,
+            WindowFunction::BlackmanHarris,
+        ] {
+            let config = config(function, Estimator::Fft);
+            let mut sliding = SlidingDft::new(&config).unwrap();
+            // 直接計算し直すところを何度かまたぐ長さにする
+            let samples = input(config.window_size * 5 + 17, config.channels);
+
+            let mut windows = Vec::new();
+            for frame in samples.chunks_exact(config.channels) {
+                if let Some(index) = sliding.push_frame(frame) {
+                    let mut values = Vec::new();
+                    for chan in 0..config.channels {
+                        sliding.estimate(chan, &mut values);
+                        let expected = expected(&config, &samples, chan, index);
+                        for (s, e) in values.iter().zip(expected.iter()) {
+                            assert!((s - e).norm() < 1e-3, "{:?}: {} != {}", function, s, e);
+                        }
+                    }
+                    windows.push(index);
+                }
+            }
+            // 窓が揃ってから hop ごとに結果を出す
+            assert_eq!(windows[0], 0);
+            assert!(windows.windows(2).all(|w| w[1] - w[0] == config.hop_size));
+            let frames = samples.len() / config.channels;
+            let last = (frames - config.window_size) / config.hop_size * config.hop_size;
+            assert_eq!(*windows.last().unwrap(), last);
+        }
+    }
+
+    #[test]
+    fn reset_waits_for_a_full_window() {
+        let config = config(WindowFunction::Hann, Estimator::Fft);
+        let mut sliding = SlidingDft::new(&config).unwrap();
+        let samples = input(config.window_size * 3, config.channels);
+        let (before, after) = samples.split_at(samples.len() / 3);
+        for frame in before.chunks_exact(config.channels) {
+            sliding.push_frame(frame);
+        }
+
+        let index = sliding.reset();
+        assert_eq!(index, config.window_size);
+        let first = after
+            .chunks_exact(config.channels)
+            .find_map(|frame| sliding.push_frame(frame));
+        assert_eq!(first, Some(index));
+
+        let mut values = Vec::new();
+        sliding.estimate(0, &mut values);
+        let expected = expected(&config, &samples, 0, index);
+        for (s, e) in values.iter().zip(expected.iter()) {
+            assert!((s - e).norm() < 1e-3, "{} != {}", s, e);
+        }
+    }
+
+    #[test]
+    fn kaiser_is_not_supported() {
+        let config = config(WindowFunction::Kaiser, Estimator::Fft);
+        assert!(SlidingDft::new(&config).is_err());
+    }
+}
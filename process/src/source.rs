@@ -0,0 +1,21 @@
+use hound::WavSpec;
+
+use super::error::Result;
+use super::packet::Packet;
+
+/// FFT や制御のパイプラインに音声を供給するもの
+///
+/// WASAPI の loopback もこれの実装の一つで、capture スレッドはこの trait を通してのみ音声を受け取る
+pub trait AudioSource {
+    /// 取得を開始して、実際に使う wave format を返す
+    fn start(&mut self) -> Result<WavSpec>;
+
+    /// 次の packet を返す
+    ///
+    /// データが来るまで待つことがある。待ってもデータが来なかったときはフレームを持たない packet を、
+    /// これ以上データがない場合は None を返す
+    fn read(&mut self) -> Result<Option<Packet>>;
+
+    /// 取得を終了する
+    fn stop(&mut self) -> Result<()>;
+}
@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::error::Result;
+
+struct Inner {
+    is_stopped: AtomicBool,
+    lock: Mutex<()>,
+    cond: Condvar,
+}
+
+/// セッションを止めるためのハンドル
+///
+/// clone して Ctrl-C のハンドラや別のスレッドに渡し、stop を呼ぶとセッションが終了処理に入る
+#[derive(Clone)]
+pub struct StopHandle {
+    inner: Arc<Inner>,
+}
+
+impl Default for StopHandle {
+    fn default() -> Self {
+        StopHandle::new()
+    }
+}
+
+impl StopHandle {
+    pub fn new() -> StopHandle {
+        StopHandle {
+            inner: Arc::new(Inner {
+                is_stopped: AtomicBool::new(false),
+                lock: Mutex::new(()),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+
+    /// 止めるように要求する。何度呼んでもよい
+    pub fn stop(&self) {
+        self.inner.is_stopped.store(true, SeqCst);
+        let _lock = self.inner.lock.lock().unwrap();
+        self.inner.cond.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.inner.is_stopped.load(SeqCst)
+    }
+
+    /// stop が呼ばれるか、timeout が経つまで待つ。timeout が None なら stop が呼ばれるまで待つ
+    pub fn wait(&self, timeout: Option<Duration>) {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut lock = self.inner.lock.lock().unwrap();
+        while !self.is_stopped() {
+            lock = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return;
+                    }
+                    self.inner
+                        .cond
+                        .wait_timeout(lock, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.inner.cond.wait(lock).unwrap(),
+            };
+        }
+    }
+
+    /// result がエラーなら、他のスレッドも終了処理に入れるように stop してからそのまま返す
+    pub(crate) fn stop_on_error<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.stop();
+        }
+        result
+    }
+}
@@ -0,0 +1,33 @@
+//! 複数のモジュールのテストで使う設定と入力
+
+use hound::{SampleFormat, WavSpec};
+use std::path::PathBuf;
+
+use super::config::{PipelineConfig, PipelineOptions};
+
+/// 48kHz の device で options を使うときの PipelineConfig
+pub fn config(channels: u16, options: PipelineOptions) -> PipelineConfig {
+    let spec = WavSpec {
+        channels,
+        sample_rate: 48000,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    PipelineConfig::new(&spec, &options).unwrap()
+}
+
+/// 決まった系列になるように、sin を組み合わせた bin の中心にない音にする。channel ごとに違う音になる
+pub fn input(frames: usize, channels: usize) -> Vec<f32> {
+    (0..frames * channels)
+        .map(|n| {
+            let t = (n / channels) as f32;
+            let chan = (n % channels) as f32 + 1.0;
+            (0.37 * t * chan).sin() * 0.5 + (0.0131 * t).cos() * 0.3 + (2.9 * t).sin() * 0.1
+        })
+        .collect()
+}
+
+/// テストごとに別の一時ファイルのパス。同時に走る他のテストや、他のプロセスと重ならないようにする
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("filterg-{}-{}", std::process::id(), name))
+}
@@ -0,0 +1,257 @@
+use super::device::find_device;
+use super::utils::{
+    get_sample_format, AudioClientStopOnExit, CancelWaitableTimerOnExit, CloseHandleOnExit,
+    CoTaskMemFreeOnExit, CoUninitializeOnExit,
+};
+use crate::config::CaptureMode;
+use crate::device::Direction;
+use crate::error::{Error, Result};
+use crate::packet::{Packet, PacketFlags};
+use crate::sample_format::SampleFormat;
+use crate::source::AudioSource;
+use bindings::Windows::Win32::Media::Audio::CoreAudio::{
+    IAudioCaptureClient, IAudioClient3, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_LOOPBACK,
+};
+use bindings::Windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+use bindings::Windows::Win32::System::Threading::{
+    CreateWaitableTimerW, SetWaitableTimer, WaitForMultipleObjects, WAIT_OBJECT_0,
+};
+use bindings::Windows::Win32::{Foundation::HANDLE, Media::Audio::CoreAudio::IMMDevice};
+use hound::WavSpec;
+use std::{mem, ptr};
+use windows::Interface;
+
+/// WASAPI で音を取得する source
+///
+/// loopback なら render device に出ている音を、microphone なら capture device に入ってくる音を取得する
+pub struct DeviceSource {
+    _cancel_timer: Option<CancelWaitableTimerOnExit>,
+    _stop: Option<AudioClientStopOnExit>,
+    _h_wake_up: Option<CloseHandleOnExit>,
+    h_wake_up: HANDLE,
+    audio_capture_client: Option<IAudioCaptureClient>,
+    audio_client: IAudioClient3,
+    stream_flags: u32,
+    n_channel: u16,
+    format: SampleFormat,
+    passes: u64,
+    frames: u64,
+    // COM の解放は最後にする
+    _com: CoUninitializeOnExit,
+}
+
+impl DeviceSource {
+    /// このスレッドで COM を初期化して、ID か名前の一部が device に合う device を開く。None なら既定の device
+    ///
+    /// loopback なら render device を、microphone なら capture device を探す
+    pub fn open(capture: CaptureMode, device: Option<&str>) -> Result<DeviceSource> {
+        unsafe { CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED)? };
+        let com = CoUninitializeOnExit {};
+
+        let (direction, stream_flags) = match capture {
+            CaptureMode::Loopback => (Direction::Render, AUDCLNT_STREAMFLAGS_LOOPBACK),
+            CaptureMode::Microphone => (Direction::Capture, 0),
+        };
+        let device = find_device(direction, device)?;
+
+        DeviceSource::new(&device, stream_flags, com)
+    }
+
+    fn new(
+        mm_device: &IMMDevice,
+        stream_flags: u32,
+        com: CoUninitializeOnExit,
+    ) -> Result<DeviceSource> {
+        // TODO: https://docs.microsoft.com/en-us/windows-hardware/drivers/audio/low-latency-audio#windows-audio-session-api-wasapi
+        let audio_client: IAudioClient3 = unsafe {
+            let mut audio_client = ptr::null_mut();
+
+            mm_device.Activate(&IAudioClient3::IID, 0x17, ptr::null(), &mut audio_client)?;
+            mem::transmute::<_, IAudioClient3>(audio_client)
+        };
+
+        Ok(DeviceSource {
+            _cancel_timer: None,
+            _stop: None,
+            _h_wake_up: None,
+            h_wake_up: HANDLE(0),
+            audio_capture_client: None,
+            audio_client,
+            stream_flags,
+            n_channel: 0,
+            format: SampleFormat::F32,
+            passes: 0,
+            frames: 0,
+            _com: com,
+        })
+    }
+}
+
+impl AudioSource for DeviceSource {
+    fn start(&mut self) -> Result<WavSpec> {
+        let mut hns_default_device_period = 0;
+        unsafe {
+            self.audio_client
+                .GetDevicePeriod(&mut hns_default_device_period, &mut 0)?
+        };
+        println!("hns_default_device_period: {}", hns_default_device_period);
+
+        // mix format のまま開き、サンプルは read で f32 に変換する
+        let wfx = unsafe { self.audio_client.GetMixFormat()? };
+        // Initialize に渡し終えたら解放する
+        let _wfx = CoTaskMemFreeOnExit { ptr: wfx };
+        self.format = get_sample_format(wfx)?;
+        self.n_channel = unsafe { (*wfx).nChannels };
+
+        let spec = self
+            .format
+            .to_spec(self.n_channel, unsafe { (*wfx).nSamplesPerSec });
+
+        let h_wake_up = unsafe { CreateWaitableTimerW(ptr::null(), false, None) };
+        if h_wake_up == HANDLE(0) {
+            return Err(windows::Error::from_win32().into());
+        }
+        self.h_wake_up = h_wake_up;
+        self._h_wake_up = Some(CloseHandleOnExit { handle: h_wake_up });
+
+        unsafe {
+            self.audio_client.Initialize(
+                AUDCLNT_SHAREMODE_SHARED,
+                self.stream_flags,
+                0,
+                0,
+                wfx,
+                ptr::null(),
+            )?
+        };
+
+        self.audio_capture_client = Some(unsafe {
+            let mut audio_capture_client = ptr::null_mut();
+
+            self.audio_client
+                .GetService(&IAudioCaptureClient::IID, &mut audio_capture_client)?;
+            mem::transmute::<_, IAudioCaptureClient>(audio_capture_client)
+        });
+
+        // TODO: AvSetMmThreadCharacteristics を呼ぶか work queue を使うようにする(非オーディオサブシステムによる干渉のムラをなくす？)
+
+        // let task = unsafe {
+        //     winapi::um::avrt::AvSetMmThreadCharacteristicsW(
+        //         OsStr::new("Audio")
+        //             .encode_wide()
+        //             .chain(std::iter::once(0))
+        //             .collect::<Vec<_>>()
+        //             .as_ptr(),
+        //         &mut 0,
+        //     )
+        // };
+        // println!("task.isnull: {}", task.is_null());
+        // if task.is_null() {
+        //     println!("{:#?}", unsafe { GetLastError() })
+        // }
+        // let _task = AvRevertMmThreadCharacteristicsOnExit { h: task };
+
+        let b_ok = unsafe {
+            SetWaitableTimer(
+                h_wake_up,
+                &(-hns_default_device_period / 2),
+                (hns_default_device_period / 2 / (10 * 1000)) as i32, // hns_default_device_period / 2ms
+                None,
+                ptr::null(),
+                false,
+            )
+        };
+        if !b_ok.as_bool() {
+            return Err(windows::Error::from_win32().into());
+        }
+        self._cancel_timer = Some(CancelWaitableTimerOnExit { handle: h_wake_up });
+
+        unsafe { self.audio_client.Start()? };
+        self._stop = Some(AudioClientStopOnExit {
+            client: self.audio_client.clone(),
+        });
+
+        Ok(spec)
+    }
+
+    fn read(&mut self) -> Result<Option<Packet>> {
+        let audio_capture_client = match &self.audio_capture_client {
+            Some(client) => client,
+            None => return Err(Error::Device("read before start".to_string())),
+        };
+
+        // 前回の timer で溜まった packet がなければ timer をまつ
+        let mut next_packet_size = unsafe { audio_capture_client.GetNextPacketSize()? };
+        if next_packet_size == 0 {
+            let wait_result =
+                unsafe { WaitForMultipleObjects(1, &self.h_wake_up, false, u32::MAX) };
+            if wait_result != WAIT_OBJECT_0 {
+                return Err(Error::Device(format!(
+                    "Unexpected WaitForMultipleObjects return value {:?} on pass {} after {} frames",
+                    wait_result, self.passes, self.frames
+                )));
+            }
+            self.passes += 1;
+
+            next_packet_size = unsafe { audio_capture_client.GetNextPacketSize()? };
+            if next_packet_size == 0 {
+                return Ok(Some(Packet::empty()));
+            }
+        }
+
+        let mut data = ptr::null_mut::<u8>();
+        let mut num_frames_to_read = 0;
+        let mut flags = 0;
+        let mut qpc_position = 0;
+        unsafe {
+            audio_capture_client.GetBuffer(
+                &mut data,
+                &mut num_frames_to_read,
+                &mut flags,
+                ptr::null_mut(),
+                &mut qpc_position,
+            )?
+        }
+
+        if 0 == num_frames_to_read {
+            return Err(Error::Device(format!(
+                "IAudioCaptureClient::GetBuffer said to read 0 frames on pass {} after {} frames",
+                self.passes, self.frames
+            )));
+        }
+
+        let flags = PacketFlags(flags);
+        let n_samples = (num_frames_to_read * self.n_channel as u32) as usize;
+        // 無音の packet は data を読まずに 0 で埋める
+        let samples = if flags.is_silent() {
+            vec![0.0; n_samples]
+        } else {
+            let bytes = unsafe {
+                std::slice::from_raw_parts(data, n_samples * self.format.bytes_per_sample())
+            };
+            let mut samples = Vec::with_capacity(n_samples);
+            self.format.decode(bytes, &mut samples);
+            samples
+        };
+
+        unsafe {
+            audio_capture_client.ReleaseBuffer(num_frames_to_read)?;
+        }
+
+        self.frames += num_frames_to_read as u64;
+
+        Ok(Some(Packet {
+            samples,
+            frames: num_frames_to_read as usize,
+            flags,
+            timestamp: qpc_position,
+        }))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        // guard を drop して timer と stream を止める
+        self._cancel_timer = None;
+        self._stop = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use bindings::{
+    Windows::Win32::Media::Audio::CoreAudio::{
+        eCapture, eConsole, eRender, EDataFlow, IAudioClient3, IMMDevice, IMMDeviceEnumerator,
+        MMDeviceEnumerator,
+    },
+    Windows::Win32::{
+        Media::Audio::CoreAudio::{
+            DEVICE_STATEMASK_ALL, DEVICE_STATE_ACTIVE, DEVICE_STATE_DISABLED,
+            DEVICE_STATE_NOTPRESENT,
+        },
+        Storage::StructuredStorage::STGM_READ,
+        System::{
+            Com::{CoCreateInstance, CLSCTX_ALL},
+            SystemServices::DEVPKEY_Device_FriendlyName,
+        },
+    },
+};
+use hound::WavSpec;
+use std::{mem, ptr};
+use windows::Interface;
+
+use super::utils::{from_wide_ptr, get_sample_format, CoTaskMemFreeOnExit};
+use crate::device::{select_device, DeviceEnumerator, DeviceInfo, DeviceState, Direction};
+use crate::error::Result;
+
+/// WASAPI の endpoint を列挙する。COM を初期化したスレッドで使うこと
+pub struct WasapiEnumerator {
+    enumerator: IMMDeviceEnumerator,
+}
+
+impl WasapiEnumerator {
+    pub fn new() -> Result<WasapiEnumerator> {
+        let enumerator = unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)? };
+        Ok(WasapiEnumerator { enumerator })
+    }
+
+    fn devices_of(&self, direction: Direction, devices: &mut Vec<DeviceInfo>) -> Result<()> {
+        let flow = data_flow(direction);
+        // 既定の device がないこともある
+        let default_id = unsafe { self.enumerator.GetDefaultAudioEndpoint(flow, eConsole) }
+            .ok()
+            .and_then(|device| get_id(&device).ok());
+
+        let collection = unsafe {
+            self.enumerator
+                .EnumAudioEndpoints(flow, DEVICE_STATEMASK_ALL)?
+        };
+        let count = unsafe { collection.GetCount()? };
+
+        for i in 0..count {
+            let mm_device = unsafe { collection.Item(i)? };
+            let id = get_id(&mm_device)?;
+            let state = match unsafe { mm_device.GetState()? } {
+                DEVICE_STATE_ACTIVE => DeviceState::Active,
+                DEVICE_STATE_DISABLED => DeviceState::Disabled,
+                DEVICE_STATE_NOTPRESENT => DeviceState::NotPresent,
+                _ => DeviceState::Unplugged,
+            };
+            let mix_format = if state == DeviceState::Active {
+                get_mix_format(&mm_device)
+            } else {
+                None
+            };
+
+            devices.push(DeviceInfo {
+                is_default: default_id.as_deref() == Some(id.as_str()),
+                id,
+                // 取り外された device などは名前を持たないことがある
+                name: get_friendly_name(&mm_device).unwrap_or_default(),
+                direction,
+                state,
+                mix_format,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl DeviceEnumerator for WasapiEnumerator {
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        let mut devices = Vec::new();
+        self.devices_of(Direction::Render, &mut devices)?;
+        self.devices_of(Direction::Capture, &mut devices)?;
+        Ok(devices)
+    }
+}
+
+/// direction の device を ID か名前の一部で選んで開く。query が None なら既定の device
+pub fn find_device(direction: Direction, query: Option<&str>) -> Result<IMMDevice> {
+    let enumerator = WasapiEnumerator::new()?;
+    let info = select_device(&enumerator, direction, query)?;
+    println!("{:?} device: {} ({})", direction, info.name, info.id);
+
+    let device = unsafe { enumerator.enumerator.GetDevice(info.id.as_str())? };
+    Ok(device)
+}
+
+fn data_flow(direction: Direction) -> EDataFlow {
+    match direction {
+        Direction::Render => eRender,
+        Direction::Capture => eCapture,
+    }
+}
+
+fn get_id(mm_device: &IMMDevice) -> Result<String> {
+    let id = unsafe { mm_device.GetId()? };
+    let _id = CoTaskMemFreeOnExit { ptr: id.0 };
+    Ok(from_wide_ptr(id.0))
+}
+
+fn get_friendly_name(mm_device: &IMMDevice) -> Result<String> {
+    let property_store = unsafe { mm_device.OpenPropertyStore(STGM_READ as u32)? };
+    let pv = unsafe { property_store.GetValue(&DEVPKEY_Device_FriendlyName)? };
+    let name = unsafe { pv.Anonymous.Anonymous.Anonymous.pwszVal.0 };
+    if name.is_null() {
+        return Ok(String::new());
+    }
+    Ok(from_wide_ptr(name))
+}
+
+/// 共有モードの mix format。扱えない形式なら None
+fn get_mix_format(mm_device: &IMMDevice) -> Option<WavSpec> {
+    let audio_client: IAudioClient3 = unsafe {
+        let mut audio_client = ptr::null_mut();
+        mm_device
+            .Activate(&IAudioClient3::IID, 0x17, ptr::null(), &mut audio_client)
+            .ok()?;
+        mem::transmute::<_, IAudioClient3>(audio_client)
+    };
+    let wfx = unsafe { audio_client.GetMixFormat().ok()? };
+    let _wfx = CoTaskMemFreeOnExit { ptr: wfx };
+    let format = get_sample_format(wfx).ok()?;
+    Some(format.to_spec(unsafe { (*wfx).nChannels }, unsafe {
+        (*wfx).nSamplesPerSec
+    }))
+}
@@ -0,0 +1,7 @@
+//! WASAPI と COM を使う Windows 向けの backend
+
+pub mod capture;
+pub mod device;
+pub mod event;
+pub mod render;
+pub mod utils;
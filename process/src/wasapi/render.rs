@@ -0,0 +1,209 @@
+use super::device::find_device;
+use super::event::create_event;
+use super::utils::{
+    get_sample_format, AudioClientStopOnExit, CoTaskMemFreeOnExit, CoUninitializeOnExit,
+    AUDCLNT_BUFFERFLAGS_SILENT,
+};
+use crate::device::Direction;
+use crate::error::{Error, Result};
+use crate::sample_format::SampleFormat;
+use crate::sink::AudioSink;
+use bindings::Windows::Win32::Foundation::HANDLE;
+use bindings::Windows::Win32::Media::Audio::CoreAudio::IMMDevice;
+use bindings::Windows::Win32::Media::Audio::CoreAudio::{
+    IAudioClient3, IAudioRenderClient, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+};
+use bindings::Windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+use bindings::Windows::Win32::System::Threading::{WaitForMultipleObjects, WAIT_OBJECT_0};
+use hound::WavSpec;
+use std::{mem, ptr};
+use windows::Interface;
+
+/// WASAPI で render device に音を出す sink
+pub struct DeviceSink {
+    _stop: Option<AudioClientStopOnExit>,
+    h_feed_me: HANDLE,
+    audio_render_client: Option<IAudioRenderClient>,
+    audio_client: IAudioClient3,
+    frames_in_buffer: u32,
+    blockalign: u16,
+    channel_count: u16,
+    format: SampleFormat,
+    passes: u64,
+    // COM の解放は最後にする
+    _com: CoUninitializeOnExit,
+}
+
+impl DeviceSink {
+    /// このスレッドで COM を初期化して、ID か名前の一部が device に合う render device を開く。None なら既定の device
+    pub fn open(device: Option<&str>) -> Result<DeviceSink> {
+        unsafe { CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED)? };
+        let com = CoUninitializeOnExit {};
+
+        let device = find_device(Direction::Render, device)?;
+
+        DeviceSink::new(&device, com)
+    }
+
+    fn new(mm_device: &IMMDevice, com: CoUninitializeOnExit) -> Result<DeviceSink> {
+        // TODO: https://docs.microsoft.com/en-us/windows-hardware/drivers/audio/low-latency-audio#windows-audio-session-api-wasapi
+        let audio_client: IAudioClient3 = unsafe {
+            let mut audio_client = ptr::null_mut();
+
+            mm_device.Activate(&IAudioClient3::IID, 0x17, ptr::null(), &mut audio_client)?;
+            mem::transmute::<_, IAudioClient3>(audio_client)
+        };
+
+        Ok(DeviceSink {
+            _stop: None,
+            h_feed_me: HANDLE(0),
+            audio_render_client: None,
+            audio_client,
+            frames_in_buffer: 0,
+            blockalign: 0,
+            channel_count: 0,
+            format: SampleFormat::F32,
+            passes: 0,
+            _com: com,
+        })
+    }
+
+    fn render_client(&self) -> Result<&IAudioRenderClient> {
+        match &self.audio_render_client {
+            Some(client) => Ok(client),
+            None => Err(Error::Device("render client is not started".to_string())),
+        }
+    }
+}
+
+impl AudioSink for DeviceSink {
+    fn start(&mut self) -> Result<WavSpec> {
+        // mix format のまま開き、サンプルは write で f32 から変換する
+        let wfx = unsafe { self.audio_client.GetMixFormat()? };
+        // Initialize に渡し終えたら解放する
+        let _wfx = CoTaskMemFreeOnExit { ptr: wfx };
+        self.format = get_sample_format(wfx)?;
+
+        self.blockalign = unsafe { (*wfx).nBlockAlign };
+        self.channel_count = unsafe { (*wfx).nChannels };
+
+        let spec = self
+            .format
+            .to_spec(self.channel_count, unsafe { (*wfx).nSamplesPerSec });
+
+        unsafe {
+            self.audio_client.Initialize(
+                AUDCLNT_SHAREMODE_SHARED,
+                AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+                0,
+                0,
+                wfx,
+                ptr::null(),
+            )?
+        };
+
+        self.frames_in_buffer = unsafe { self.audio_client.GetBufferSize()? };
+
+        let audio_render_client = unsafe {
+            let mut audio_render_client = ptr::null_mut();
+
+            self.audio_client
+                .GetService(&IAudioRenderClient::IID, &mut audio_render_client)?;
+            mem::transmute::<_, IAudioRenderClient>(audio_render_client)
+        };
+
+        self.h_feed_me = create_event()?;
+
+        unsafe {
+            self.audio_client.SetEventHandle(self.h_feed_me)?;
+        };
+
+        let _data = unsafe { audio_render_client.GetBuffer(self.frames_in_buffer)? };
+
+        unsafe {
+            audio_render_client.ReleaseBuffer(self.frames_in_buffer, AUDCLNT_BUFFERFLAGS_SILENT)?
+        };
+        self.audio_render_client = Some(audio_render_client);
+
+        // TODO: AvSetMmThreadCharacteristics を呼ぶか work queue を使うようにする(非オーディオサブシステムによる干渉のムラをなくす？)
+
+        // let task = unsafe {
+        //     winapi::um::avrt::AvSetMmThreadCharacteristicsW(
+        //         OsStr::new("Audio")
+        //             .encode_wide()
+        //             .chain(std::iter::once(0))
+        //             .collect::<Vec<_>>()
+        //             .as_ptr(),
+        //         &mut 0,
+        //     )
+        // };
+        // println!("task.isnull: {}", task.is_null());
+        // if task.is_null() {
+        //     println!("{:#?}", unsafe { GetLastError() })
+        // }
+        // let _task = AvRevertMmThreadCharacteristicsOnExit { h: task };
+
+        unsafe { self.audio_client.Start()? };
+        self._stop = Some(AudioClientStopOnExit {
+            client: self.audio_client.clone(),
+        });
+
+        Ok(spec)
+    }
+
+    fn wait_writable(&mut self) -> Result<usize> {
+        // event をまつ
+        let wait_result = unsafe { WaitForMultipleObjects(1, &self.h_feed_me, false, u32::MAX) };
+        if wait_result != WAIT_OBJECT_0 {
+            return Err(Error::Device(format!(
+                "Unexpected WaitForMultipleObjects return value {:#?} on pass {}",
+                wait_result, self.passes
+            )));
+        }
+        self.passes += 1;
+
+        let frames_of_padding = unsafe { self.audio_client.GetCurrentPadding()? };
+        let available_frames = self.frames_in_buffer - frames_of_padding;
+
+        if available_frames == 0 {
+            println!("[ERROR?] Got \"feed me\" event but IAudioClient::GetCurrentPadding reports buffer is full - glitch?");
+            // return Err(Error::Device(
+            //     "Got \"feed me\" event but IAudioClient::GetCurrentPadding reports buffer is full - glitch?"
+            // ));
+        }
+
+        Ok(available_frames as usize)
+    }
+
+    fn write(&mut self, samples: &[f32], is_silent: bool) -> Result<()> {
+        let audio_render_client = self.render_client()?;
+        let available_frames = (samples.len() / self.channel_count as usize) as u32;
+
+        let data = unsafe { audio_render_client.GetBuffer(available_frames)? };
+
+        let data_slice = unsafe {
+            std::slice::from_raw_parts_mut(
+                data,
+                (available_frames * self.blockalign as u32) as usize,
+            )
+        };
+
+        self.format.encode(samples, data_slice);
+
+        let flag = if is_silent {
+            AUDCLNT_BUFFERFLAGS_SILENT
+        } else {
+            0
+        };
+
+        unsafe { audio_render_client.ReleaseBuffer(available_frames, flag)? };
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        // guard を drop して stream を止める
+        self._stop = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use crate::error::Result;
+use crate::sample_format::SampleFormat;
+use bindings::Windows::Win32::Foundation::{CloseHandle, HANDLE};
+use bindings::Windows::Win32::Media::Audio::CoreAudio::IAudioClient3;
+use bindings::Windows::Win32::Media::Multimedia::WAVEFORMATEX;
+use bindings::Windows::Win32::System::Com::{CoTaskMemFree, CoUninitialize};
+use bindings::Windows::Win32::System::Diagnostics::Debug::GetLastError;
+use bindings::Windows::Win32::System::Threading::CancelWaitableTimer;
+use std::ptr;
+
+pub struct CoUninitializeOnExit {}
+
+impl Drop for CoUninitializeOnExit {
+    fn drop(&mut self) {
+        unsafe { CoUninitialize() };
+    }
+}
+
+/// GetId や GetMixFormat が CoTaskMemAlloc で確保して返したメモリを解放する
+pub struct CoTaskMemFreeOnExit<T> {
+    pub ptr: *mut T,
+}
+
+impl<T> Drop for CoTaskMemFreeOnExit<T> {
+    fn drop(&mut self) {
+        unsafe { CoTaskMemFree(self.ptr as *const std::ffi::c_void) };
+    }
+}
+
+pub struct CloseHandleOnExit {
+    pub handle: HANDLE,
+}
+
+impl Drop for CloseHandleOnExit {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.handle) };
+    }
+}
+
+pub struct CancelWaitableTimerOnExit {
+    pub handle: HANDLE,
+}
+
+impl Drop for CancelWaitableTimerOnExit {
+    fn drop(&mut self) {
+        let result = unsafe { CancelWaitableTimer(self.handle) };
+        if !result.as_bool() {
+            panic!("panic in drop CancelWaitableTimerOnExit {:#?}", unsafe {
+                GetLastError()
+            });
+        }
+    }
+}
+
+pub struct AudioClientStopOnExit {
+    pub client: IAudioClient3,
+}
+
+impl Drop for AudioClientStopOnExit {
+    fn drop(&mut self) {
+        unsafe { self.client.Stop() }.unwrap();
+    }
+}
+
+// pub struct AvRevertMmThreadCharacteristicsOnExit {
+//     pub h: *mut winapi::ctypes::c_void,
+// }
+
+// impl Drop for AvRevertMmThreadCharacteristicsOnExit {
+//     fn drop(&mut self) {
+//         unsafe { winapi::um::avrt::AvRevertMmThreadCharacteristics(self.h) };
+//     }
+// }
+
+pub fn from_wide_ptr(ptr: *const u16) -> String {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+    unsafe {
+        assert!(!ptr.is_null());
+        let len = (0..std::isize::MAX)
+            .position(|i| *ptr.offset(i) == 0)
+            .unwrap();
+        let slice = std::slice::from_raw_parts(ptr, len);
+        OsString::from_wide(slice).to_string_lossy().into_owned()
+    }
+}
+
+pub const AUDCLNT_BUFFERFLAGS_SILENT: u32 = 2;
+
+pub const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// WAVEFORMATEXTENSIBLE の SubFormat の位置と、そこまでに必要な cbSize
+const SUB_FORMAT_OFFSET: usize = 24;
+const EXTENSIBLE_CB_SIZE: u16 = 22;
+
+/// GetMixFormat が返した wave format のサンプルの形式
+///
+/// WAVE_FORMAT_EXTENSIBLE の場合は SubFormat の GUID の先頭 4 byte が WAVE_FORMAT_* と同じ値になっているのでそれを使う
+pub fn get_sample_format(wfx: *const WAVEFORMATEX) -> Result<SampleFormat> {
+    let (format_tag, bits_per_sample, cb_size) =
+        unsafe { ((*wfx).wFormatTag, (*wfx).wBitsPerSample, (*wfx).cbSize) };
+    let format_tag = if format_tag == WAVE_FORMAT_EXTENSIBLE && cb_size >= EXTENSIBLE_CB_SIZE {
+        unsafe {
+            ptr::read_unaligned((wfx as *const u8).add(SUB_FORMAT_OFFSET) as *const u32) as u16
+        }
+    } else {
+        format_tag
+    };
+    SampleFormat::from_wave_format(format_tag, bits_per_sample)
+}
@@ -0,0 +1,206 @@
+use hound::{WavReader, WavSpec, WavWriter};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use super::error::{Error, Result};
+use super::packet::{frames_to_timestamp, Packet};
+use super::sample_format::SampleFormat;
+use super::sink::AudioSink;
+use super::source::AudioSource;
+
+/// WAV ファイルから音声を読み込む source
+pub struct WavFileSource {
+    reader: WavReader<BufReader<File>>,
+    format: SampleFormat,
+    /// hound が読んだサンプルを、device の buffer と同じ little endian のバイト列に戻したもの
+    bytes: Vec<u8>,
+    block_frames: usize,
+    /// 次に読むフレームの位置
+    position: u64,
+}
+
+impl WavFileSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<WavFileSource> {
+        let reader = WavReader::open(path)?;
+        let format = SampleFormat::from_spec(&reader.spec())?;
+        // hound は 64bit float のサンプルを読めないので、読み始める前に断る
+        if format == SampleFormat::F64 {
+            return Err(Error::Format(
+                "64bit float WAV is not supported".to_string(),
+            ));
+        }
+        // 一度に 1ms 分ずつ読む
+        let block_frames = (reader.spec().sample_rate as usize / 1000).max(1);
+        Ok(WavFileSource {
+            reader,
+            format,
+            bytes: Vec::new(),
+            block_frames,
+            position: 0,
+        })
+    }
+}
+
+impl AudioSource for WavFileSource {
+    fn start(&mut self) -> Result<WavSpec> {
+        Ok(self.reader.spec())
+    }
+
+    fn read(&mut self) -> Result<Option<Packet>> {
+        let spec = self.reader.spec();
+        let n_samples = self.block_frames * spec.channels as usize;
+        // 変換は device と同じ SampleFormat::decode で行う
+        let width = self.format.bytes_per_sample();
+        self.bytes.clear();
+        if self.format == SampleFormat::F32 {
+            for sample in self.reader.samples::<f32>().take(n_samples) {
+                self.bytes.extend_from_slice(&sample?.to_le_bytes());
+            }
+        } else {
+            // hound は整数のサンプルを bits_per_sample の範囲のまま i32 で返すので、下位の width byte がそのサンプルになる
+            for sample in self.reader.samples::<i32>().take(n_samples) {
+                self.bytes
+                    .extend_from_slice(&sample?.to_le_bytes()[..width]);
+            }
+        }
+        let mut buffer = Vec::with_capacity(n_samples);
+        self.format.decode(&self.bytes, &mut buffer);
+
+        let read_frames = buffer.len() / spec.channels as usize;
+        if read_frames == 0 {
+            return Ok(None);
+        }
+
+        let timestamp = frames_to_timestamp(self.position, spec.sample_rate);
+        self.position += read_frames as u64;
+        Ok(Some(Packet {
+            samples: buffer,
+            frames: read_frames,
+            flags: Default::default(),
+            timestamp,
+        }))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// 32bit float の WAV ファイルに書き出す sink
+pub struct WavFileSink {
+    writer: Option<WavWriter<BufWriter<File>>>,
+    spec: WavSpec,
+}
+
+impl WavFileSink {
+    /// channels と sample_rate は書き出すファイルのもの
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, sample_rate: u32) -> Result<WavFileSink> {
+        let spec = WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let writer = WavWriter::create(path, spec)?;
+        Ok(WavFileSink {
+            writer: Some(writer),
+            spec,
+        })
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn start(&mut self) -> Result<WavSpec> {
+        Ok(self.spec)
+    }
+
+    fn wait_writable(&mut self) -> Result<usize> {
+        // ファイルはいつでも書き込めるので、1ms 分を返す
+        Ok((self.spec.sample_rate as usize / 1000).max(1))
+    }
+
+    fn write(&mut self, samples: &[f32], is_silent: bool) -> Result<()> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return Err(Error::Device("write after stop".to_string())),
+        };
+        for sample in samples {
+            let sample = if is_silent { 0.0 } else { *sample };
+            writer.write_sample(sample)?;
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.finalize()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_format::{f32_to_i16, f32_to_i24, f32_to_i32};
+    use crate::test_utils::temp_path;
+
+    #[test]
+    fn reads_integer_samples_like_device_buffers() {
+        let samples = [0.0, 0.5, -0.5, 0.25, -1.0, 0.123_456];
+        for format in [SampleFormat::I16, SampleFormat::I24, SampleFormat::I32] {
+            let path = temp_path("wav-integer.wav");
+            let mut bytes = vec![0u8; samples.len() * format.bytes_per_sample()];
+            format.encode(&samples, &mut bytes);
+            let mut expected = Vec::new();
+            format.decode(&bytes, &mut expected);
+
+            let spec = format.to_spec(2, 48000);
+            let mut writer = WavWriter::create(&path, spec).unwrap();
+            for sample in samples {
+                let value = match format {
+                    SampleFormat::I16 => f32_to_i16(sample) as i32,
+                    SampleFormat::I24 => f32_to_i24(sample),
+                    _ => f32_to_i32(sample),
+                };
+                writer.write_sample(value).unwrap();
+            }
+            writer.finalize().unwrap();
+
+            let mut source = WavFileSource::open(&path).unwrap();
+            assert_eq!(source.start().unwrap(), spec);
+            let packet = source.read().unwrap().unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(packet.frames, 3);
+            assert_eq!(packet.samples, expected, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn rejects_64bit_float_on_open() {
+        let path = temp_path("wav-f64.wav");
+        // hound では書けないので、fmt chunk を直接書く
+        let data = [0u8; 16];
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&3u16.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&48000u32.to_le_bytes());
+        bytes.extend_from_slice(&(48000u32 * 8).to_le_bytes());
+        bytes.extend_from_slice(&8u16.to_le_bytes());
+        bytes.extend_from_slice(&64u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        std::fs::write(&path, bytes).unwrap();
+
+        let result = WavFileSource::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
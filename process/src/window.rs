@@ -0,0 +1,354 @@
+use rustfft::num_complex::Complex32;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+use super::config::PipelineConfig;
+
+/// FFT の前に掛ける窓関数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WindowFunction {
+    /// 何も掛けない。漏れが一番大きい
+    #[default]
+    Rectangular,
+    Hann,
+    Hamming,
+    /// 4 項の Blackman-Harris。漏れがとても小さい
+    BlackmanHarris,
+    /// bin の間の周波数でも振幅の誤差が小さい
+    FlatTop,
+    /// kaiser_beta で漏れと分解能の兼ね合いを決める
+    Kaiser,
+}
+
+impl FromStr for WindowFunction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rectangular" => Ok(WindowFunction::Rectangular),
+            "hann" => Ok(WindowFunction::Hann),
+            "hamming" => Ok(WindowFunction::Hamming),
+            "blackman-harris" => Ok(WindowFunction::BlackmanHarris),
+            "flat-top" => Ok(WindowFunction::FlatTop),
+            "kaiser" => Ok(WindowFunction::Kaiser),
+            _ => Err(format!(
+                "unknown window \"{}\". expected rectangular, hann, hamming, blackman-harris, flat-top or kaiser",
+                s
+            )),
+        }
+    }
+}
+
+/// 係数を計算済みの窓と、target_freqs の bin を補正するための値
+pub struct Window {
+    coefficients: Vec<f32>,
+    /// 窓の係数の和。bin の中心の周波数の正弦波は、FFT すると振幅のこれの半分倍になる
+    sum: f32,
+    /// target_freqs の bin の値をそれぞれこれで割ると、窓の先頭での正弦波の振幅と位相になる
+    responses: Vec<Complex32>,
+    /// target_freqs の 1 サンプルあたりの周期数
+    cycles_per_sample: Vec<f64>,
+    /// target_freqs が bin の中心からずれている分 (bin 単位)
+    offsets: Vec<f64>,
+}
+
+impl Window {
+    pub fn new(config: &PipelineConfig) -> Window {
+        let size = config.window_size;
+        let coefficients: Vec<f64> = (0..size)
+            .map(|n| coefficient(config.window, config.kaiser_beta as f64, n, size))
+            .collect();
+
+        let offsets: Vec<f64> = (0..config.target_freqs.len())
+            .map(|target| {
+                config.target_freqs[target] as f64 / config.bin_width() as f64
+                    - config.target_freq_index(target) as f64
+            })
+            .collect();
+        let responses = offsets
+            .iter()
+            .map(|offset| {
+                // 窓の先頭で振幅 1、位相 0 の正弦波を入れたときの bin の値。負の周波数や他の target からの漏れは無視する
+                coefficients
+                    .iter()
+                    .enumerate()
+                    .map(|(n, w)| {
+                        let phase = 2.0 * PI * offset * n as f64 / size as f64;
+                        Complex32::from_polar((w / 2.0) as f32, phase as f32)
+                    })
+                    .sum()
+            })
+            .collect();
+
+        Window {
+            sum: coefficients.iter().sum::<f64>() as f32,
+            coefficients: coefficients.iter().map(|w| *w as f32).collect(),
+            responses,
+            cycles_per_sample: config
+                .target_freqs
+                .iter()
+                .map(|freq| *freq as f64 / config.sample_rate as f64)
+                .collect(),
+            offsets,
+        }
+    }
+
+    /// FFT する前の buffer の実部に窓を掛ける
+    pub fn apply(&self, buffer: &mut [Complex32]) {
+        for (value, w) in buffer.iter_mut().zip(self.coefficients.iter()) {
+            value.re *= w;
+        }
+    }
+
+    /// index から始まる窓の、target 番目の target_freqs の bin の値を、index 0 での正弦波の振幅と位相を表す複素数に直す
+    ///
+    /// 窓による振幅の減り (scalloping を含む) と、bin の中心からずれていることによる位相の回転を打ち消す。
+    /// さらに窓の先頭までに進んだ位相を戻すので、どの窓から求めても同じ正弦波なら同じ値になる
+    pub fn correct(&self, target: usize, index: usize, value: Complex32) -> Complex32 {
+        // 長く動かしても誤差が溜まらないように、周期の端数だけを使う
+        let cycles = (self.cycles_per_sample[target] * index as f64).fract();
+        let rotation = Complex32::from_polar(1.0, (-2.0 * PI * cycles) as f32);
+        value / self.responses[target] * rotation
+    }
+
+    /// 窓の先頭から m サンプルの間だけ index 0 で振幅 1、位相 0 の正弦波があったときに correct が返す値を、m = 0..=window_size について並べたもの
+    ///
+    /// 差を取れば、窓の途中で振幅や位相が変わった正弦波の、それぞれの区間が correct の値にどれだけ寄与するかが分かる。
+    /// 最後の値は 1 になる
+    pub fn partial_responses(&self, target: usize) -> Vec<Complex32> {
+        let size = self.coefficients.len();
+        let offset = self.offsets[target];
+        let mut sum = Complex32::new(0.0, 0.0);
+        let mut partial = Vec::with_capacity(size + 1);
+        partial.push(sum);
+        for (n, w) in self.coefficients.iter().enumerate() {
+            let phase = 2.0 * PI * offset * n as f64 / size as f64;
+            sum += Complex32::from_polar(w / 2.0, phase as f32);
+            partial.push(sum / self.responses[target]);
+        }
+        partial
+    }
+
+    /// 係数の和。スペクトルを正弦波の振幅の単位にするのに使う
+    pub fn sum(&self) -> f32 {
+        self.sum
+    }
+}
+
+/// DFT 用に周期的 (長さ size で一周) にした窓の n 番目の係数
+fn coefficient(function: WindowFunction, beta: f64, n: usize, size: usize) -> f64 {
+    let x = 2.0 * PI * n as f64 / size as f64;
+    match cosine_sum_terms(function) {
+        Some(terms) => terms
+            .iter()
+            .enumerate()
+            .map(|(k, a)| if k % 2 == 0 { 1.0 } else { -1.0 } * a * (k as f64 * x).cos())
+            .sum(),
+        None => {
+            let r = 2.0 * n as f64 / size as f64 - 1.0;
+            bessel_i0(beta * (1.0 - r * r).max(0.0).sqrt()) / bessel_i0(beta)
+        }
+    }
+}
+
+/// 窓が a0 - a1 cos(x) + a2 cos(2x) - ... と cos の和で表せるなら、その係数 a0, a1, ...
+///
+/// 周波数領域では隣の bin との重み付きの和になるので、sliding DFT でも窓を掛けられる
+pub(crate) fn cosine_sum_terms(function: WindowFunction) -> Option<&'static [f64]> {
+    match function {
+        WindowFunction::Rectangular => Some(&[1.0]),
+        WindowFunction::Hann => Some(&[0.5, 0.5]),
+        WindowFunction::Hamming => Some(&[0.54, 0.46]),
+        WindowFunction::BlackmanHarris => Some(&[0.35875, 0.48829, 0.14128, 0.01168]),
+        WindowFunction::FlatTop => Some(&[
+            0.215_578_95,
+            0.416_631_58,
+            0.277_263_158,
+            0.083_578_947,
+            0.006_947_368,
+        ]),
+        WindowFunction::Kaiser => None,
+    }
+}
+
+/// 第 1 種変形 Bessel 関数 I0 の級数展開
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..100 {
+        term *= (half / k as f64).powi(2);
+        sum += term;
+        if term < sum * 1e-16 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfft::FftPlanner;
+
+    const FUNCTIONS: [WindowFunction; 6] = [
+        WindowFunction::Rectangular,
+        WindowFunction::Hann,
+        WindowFunction::Hamming,
+        WindowFunction::BlackmanHarris,
+        WindowFunction::FlatTop,
+        WindowFunction::Kaiser,
+    ];
+
+    fn config(window: WindowFunction, target_freqs: &[f32]) -> PipelineConfig {
+        crate::test_utils::config(
+            1,
+            crate::PipelineOptions {
+                window,
+                target_freqs: target_freqs.to_vec(),
+                // 窓が長いほど負の周波数からの漏れが小さい
+                window_milli_second: 20.0,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// target_freqs のそれぞれで index 0 で振幅 amplitude、位相 angle の正弦波を足したものを、index から始まる窓で FFT し、補正した target ごとの bin を返す
+    fn estimate(
+        config: &PipelineConfig,
+        index: usize,
+        amplitude: f32,
+        angle: f32,
+    ) -> Vec<Complex32> {
+        let window = Window::new(config);
+        let mut buffer: Vec<Complex32> = (index..index + config.window_size)
+            .map(|n| {
+                let t = n as f32 / config.sample_rate as f32;
+                let sample = config
+                    .target_freqs
+                    .iter()
+                    .map(|freq| {
+                        let phase = 2.0 * std::f32::consts::PI * freq * t + angle;
+                        amplitude * phase.cos()
+                    })
+                    .sum();
+                Complex32::new(sample, 0.0)
+            })
+            .collect();
+        window.apply(&mut buffer);
+        FftPlanner::new()
+            .plan_fft_forward(config.window_size)
+            .process(&mut buffer);
+        (0..config.target_freqs.len())
+            .map(|target| window.correct(target, index, buffer[config.target_freq_index(target)]))
+            .collect()
+    }
+
+    #[test]
+    fn unbiased_on_bin() {
+        for function in FUNCTIONS {
+            let value = estimate(&config(function, &[1000.0]), 0, 0.3, 1.0)[0];
+            assert!(
+                (value.norm() - 0.3).abs() < 1e-4,
+                "{:?}: {}",
+                function,
+                value
+            );
+            assert!(
+                (value.arg() - 1.0).abs() < 1e-4,
+                "{:?}: {}",
+                function,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn unbiased_between_bins() {
+        // bin の幅は 50 Hz なので、1020 Hz は bin の中心から 0.4 bin ずれている
+        for function in FUNCTIONS {
+            let value = estimate(&config(function, &[1020.0]), 0, 0.3, -2.0)[0];
+            assert!(
+                (value.norm() - 0.3).abs() < 3e-3,
+                "{:?}: {}",
+                function,
+                value
+            );
+            assert!(
+                (value.arg() + 2.0).abs() < 1e-2,
+                "{:?}: {}",
+                function,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn several_targets() {
+        // 漏れの小さい窓なら、離れた target 同士はそれぞれの振幅と位相を推定できる
+        let config = config(WindowFunction::BlackmanHarris, &[1000.0, 3020.0]);
+        for value in estimate(&config, 0, 0.3, 0.5) {
+            assert!((value.norm() - 0.3).abs() < 3e-3, "{}", value);
+            assert!((value.arg() - 0.5).abs() < 1e-2, "{}", value);
+        }
+    }
+
+    #[test]
+    fn same_phase_from_any_window() {
+        // 窓の先頭が周期の途中にあっても、index 0 での位相に戻す
+        let config = config(WindowFunction::Hann, &[1020.0]);
+        for index in [0, 7, 241, 4803] {
+            let value = estimate(&config, index, 0.3, 0.5)[0];
+            assert!((value.norm() - 0.3).abs() < 3e-3, "{}: {}", index, value);
+            assert!((value.arg() - 0.5).abs() < 1e-2, "{}: {}", index, value);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        for function in FUNCTIONS {
+            let name = serde_json::to_value(function).unwrap();
+            let parsed: WindowFunction = name.as_str().unwrap().parse().unwrap();
+            assert_eq!(parsed, function);
+        }
+        assert!("triangle".parse::<WindowFunction>().is_err());
+    }
+
+    #[test]
+    fn partial_responses_split_a_window() {
+        // 窓の途中で位相が変わる正弦波の補正後の値は、区間ごとの寄与の和になる
+        let config = config(WindowFunction::Hann, &[1020.0]);
+        let window = Window::new(&config);
+        let partial = window.partial_responses(0);
+        assert_eq!(partial.len(), config.window_size + 1);
+        assert!((partial[config.window_size] - Complex32::new(1.0, 0.0)).norm() < 1e-4);
+
+        let switch = config.window_size / 3;
+        let before = Complex32::from_polar(0.3, 0.5);
+        let after = Complex32::from_polar(0.2, -1.0);
+        let mut buffer: Vec<Complex32> = (0..config.window_size)
+            .map(|n| {
+                let value = if n < switch { before } else { after };
+                let phase = 2.0 * PI * 1020.0 * n as f64 / config.sample_rate as f64;
+                let sample = value * Complex32::from_polar(1.0, phase as f32);
+                Complex32::new(sample.re, 0.0)
+            })
+            .collect();
+        window.apply(&mut buffer);
+        FftPlanner::new()
+            .plan_fft_forward(config.window_size)
+            .process(&mut buffer);
+        let value = window.correct(0, 0, buffer[config.target_freq_index(0)]);
+        let expected =
+            before * partial[switch] + after * (partial[config.window_size] - partial[switch]);
+        // 負の周波数からの漏れの分だけずれる
+        assert!(
+            (value - expected).norm() < 1e-2,
+            "{} != {}",
+            value,
+            expected
+        );
+    }
+}
@@ -1,7 +1,318 @@
-use process::wmain;
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
 
-fn main() {
-    wmain().unwrap();
+use process::{
+    CaptureMode, Estimator, PipelineOptions, SessionOptions, StopHandle, WindowFunction,
+};
 
-    println!("end")
+const DEFAULT_CONFIG: &str = "filterg.toml";
+
+#[derive(Parser)]
+#[clap(
+    name = "filterg",
+    version,
+    about = "特定の周波数の音を逆位相の音で打ち消す"
+)]
+struct Cli {
+    /// 設定ファイル。省略するとカレントディレクトリの filterg.toml があれば使う
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// loopback かマイクで聞こえる音を打ち消し続ける
+    Run {
+        /// 動かす時間 [s]。省略すると Ctrl-C で止めるまで動かし続ける
+        #[clap(long)]
+        duration: Option<f32>,
+        /// 使う render device の ID か名前の一部。省略すると既定の device
+        #[clap(long)]
+        device: Option<String>,
+        /// loopback ではなくマイクなどの capture device から取り込む
+        #[clap(long)]
+        microphone: bool,
+        /// 取り込む capture device の ID か名前の一部。指定すると --microphone も指定したことになる
+        #[clap(long)]
+        capture_device: Option<String>,
+        /// capture の音 (逆位相の音が足された残差) と、そこで聞こえるはずの逆位相の音をこの WAV に記録する。設定は同じ名前の .json に書き出す
+        #[clap(long)]
+        record: Option<PathBuf>,
+        #[clap(flatten)]
+        pipeline: PipelineArgs,
+    },
+    /// render device と capture device の一覧を表示する
+    Devices,
+    /// WAV ファイルに対して打ち消しを行い、残差を WAV ファイルに書き出す
+    Offline {
+        input: PathBuf,
+        output: PathBuf,
+        #[clap(flatten)]
+        pipeline: PipelineArgs,
+    },
+    /// WAV ファイルのスペクトルを表示する
+    Analyze {
+        input: PathBuf,
+        /// 表示するピークの数
+        #[clap(long, default_value_t = 10)]
+        peaks: usize,
+        #[clap(flatten)]
+        pipeline: PipelineArgs,
+    },
+    /// run --record で記録した WAV を、記録したときの設定で実時間より速く FFT と制御に通し直す
+    Replay {
+        recording: PathBuf,
+        /// RenderQueue を更新した内容を CSV に書き出す
+        #[clap(long)]
+        updates: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+struct PipelineArgs {
+    /// 打ち消したい音の周波数 [Hz]。繰り返すかカンマで区切ると複数の周波数を打ち消す
+    #[clap(
+        long = "target-freq",
+        use_value_delimiter = true,
+        multiple_occurrences = true
+    )]
+    target_freqs: Vec<f32>,
+    /// FFT の窓の長さ [ms]
+    #[clap(long)]
+    window_ms: Option<f32>,
+    /// FFT の窓をずらす間隔 [ms]
+    #[clap(long)]
+    hop_ms: Option<f32>,
+    /// FFT の前に掛ける窓関数 (rectangular, hann, hamming, blackman-harris, flat-top, kaiser)
+    #[clap(long)]
+    window: Option<WindowFunction>,
+    /// --window kaiser のときの beta
+    #[clap(long)]
+    kaiser_beta: Option<f32>,
+    /// target_freqs の bin を求める方法 (fft, goertzel, sliding-dft)
+    #[clap(long)]
+    estimator: Option<Estimator>,
+    /// FFT を実行するスレッドの数
+    #[clap(long)]
+    workers: Option<usize>,
+    /// render device に書いた音が capture に戻ってくるまでの遅れ [ms]
+    #[clap(long)]
+    latency_ms: Option<f32>,
+}
+
+impl PipelineArgs {
+    /// 指定された項目だけ options を上書きする
+    fn apply(&self, mut options: PipelineOptions) -> PipelineOptions {
+        if !self.target_freqs.is_empty() {
+            options.target_freqs = self.target_freqs.clone();
+        }
+        if let Some(window_ms) = self.window_ms {
+            options.window_milli_second = window_ms;
+        }
+        if let Some(hop_ms) = self.hop_ms {
+            options.hop_milli_second = hop_ms;
+        }
+        if let Some(window) = self.window {
+            options.window = window;
+        }
+        if let Some(kaiser_beta) = self.kaiser_beta {
+            options.kaiser_beta = kaiser_beta;
+        }
+        if let Some(estimator) = self.estimator {
+            options.estimator = estimator;
+        }
+        if let Some(workers) = self.workers {
+            options.worker_count = workers;
+        }
+        if let Some(latency_ms) = self.latency_ms {
+            options.latency_milli_second = latency_ms;
+        }
+        options
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match execute(cli.config, cli.command) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("filterg: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+/// 設定ファイルを読む。指定がなく filterg.toml もなければ既定値
+fn load_session(config: Option<PathBuf>) -> process::Result<SessionOptions> {
+    match config {
+        Some(path) => SessionOptions::load(path),
+        None if Path::new(DEFAULT_CONFIG).exists() => SessionOptions::load(DEFAULT_CONFIG),
+        None => Ok(SessionOptions::default()),
+    }
+}
+
+/// 設定ファイルは使うサブコマンドでだけ読むので、壊れていても devices や replay は動く
+fn execute(config: Option<PathBuf>, command: Command) -> process::Result<u8> {
+    match command {
+        Command::Run {
+            duration,
+            device,
+            microphone,
+            capture_device,
+            record,
+            pipeline,
+        } => {
+            let mut session = load_session(config)?;
+            if let Some(duration) = duration {
+                session.duration =
+                    Duration::try_from_secs_f32(duration)
+                        .map(Some)
+                        .map_err(|_| {
+                            process::Error::Config(format!("invalid duration: {}", duration))
+                        })?;
+            }
+            if device.is_some() {
+                session.device = device;
+            }
+            if microphone || capture_device.is_some() {
+                session.capture = CaptureMode::Microphone;
+            }
+            if capture_device.is_some() {
+                session.capture_device = capture_device;
+            }
+            if record.is_some() {
+                session.record = record;
+            }
+            session.pipeline = pipeline.apply(session.pipeline);
+
+            // Ctrl-C や SIGTERM で止める
+            let stop = StopHandle::new();
+            let stop_clone = stop.clone();
+            ctrlc::set_handler(move || stop_clone.stop())
+                .map_err(|e| process::Error::Io(std::io::Error::other(e)))?;
+
+            let code = run(session, stop)?;
+            println!("end");
+            Ok(code)
+        }
+        Command::Devices => devices(),
+        Command::Offline {
+            input,
+            output,
+            pipeline,
+        } => {
+            let session = load_session(config)?;
+            process::process_wav_file(input, output, &pipeline.apply(session.pipeline))
+        }
+        Command::Analyze {
+            input,
+            peaks,
+            pipeline,
+        } => {
+            let session = load_session(config)?;
+            let spectrum = process::analyze_wav_file(input, &pipeline.apply(session.pipeline))?;
+            println!("bin width: {:.1} Hz", spectrum.bin_width);
+            for (freq, power) in spectrum.peaks(peaks) {
+                println!("{:>10.1} Hz {:>8.1} dB", freq, power);
+            }
+            Ok(0)
+        }
+        Command::Replay { recording, updates } => {
+            let report = process::replay_recording(recording)?;
+            println!(
+                "replay: frames: {}, updates: {}, digest: {:016x}",
+                report.frames,
+                report.updates.len(),
+                report.digest()
+            );
+            if !report.recorded.is_empty() {
+                println!(
+                    "replay: recorded updates: {}, matches recording: {}",
+                    report.recorded.len(),
+                    report.matches_recording()
+                );
+            }
+            if let Some(path) = updates {
+                // f32 の Display は読み戻すと同じ値になる桁数で出るので、そのまま比べられる
+                let mut csv = String::from("index,chan,target,amplitude,angle,position\n");
+                for update in report.updates.iter() {
+                    csv += &format!(
+                        "{},{},{},{},{},{}\n",
+                        update.index,
+                        update.chan,
+                        update.target,
+                        update.amplitude,
+                        update.angle,
+                        update.position
+                    );
+                }
+                std::fs::write(path, csv).map_err(process::Error::Io)?;
+            }
+            Ok(0)
+        }
+    }
+}
+
+#[cfg(windows)]
+fn run(session: SessionOptions, stop: StopHandle) -> process::Result<u8> {
+    process::wmain(session, stop)
+}
+
+#[cfg(not(windows))]
+fn run(session: SessionOptions, stop: StopHandle) -> process::Result<u8> {
+    use process::{Disturbance, SimConfig};
+
+    if session.device.is_some() || session.capture_device.is_some() {
+        return Err(process::Error::Config(
+            "selecting devices is only supported on Windows".to_string(),
+        ));
+    }
+
+    // WASAPI が使えないので、仮想的な音響ループで動かす
+    // 足しても振幅が 0.5 を超えないようにする
+    let freqs: Vec<f64> = session
+        .pipeline
+        .target_freqs
+        .iter()
+        .map(|freq| *freq as f64)
+        .collect();
+    let config = SimConfig::new(Disturbance::Tones {
+        amplitude: 0.5 / freqs.len() as f64,
+        freqs,
+    });
+    process::run_simulated(config, session, stop)
+}
+
+#[cfg(windows)]
+fn devices() -> process::Result<u8> {
+    for device in process::list_devices()? {
+        // 既定の device には * を付ける
+        println!(
+            "{} {:?} {:?} {}",
+            if device.is_default { "*" } else { " " },
+            device.direction,
+            device.state,
+            device.name
+        );
+        println!("    id: {}", device.id);
+        if let Some(spec) = device.mix_format {
+            println!(
+                "    mix format: {} ch, {} Hz, {} bit {:?}",
+                spec.channels, spec.sample_rate, spec.bits_per_sample, spec.sample_format
+            );
+        }
+    }
+    Ok(0)
+}
+
+#[cfg(not(windows))]
+fn devices() -> process::Result<u8> {
+    Err(process::Error::Device(
+        "listing devices is only supported on Windows".to_string(),
+    ))
 }